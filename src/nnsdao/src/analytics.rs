@@ -0,0 +1,273 @@
+//! Statistics of the DAO, kept up to date from the event log and from every write of a
+//! member so queries do not scan members or proposals.
+
+use crate::dao::MemberItems;
+use crate::events::{Event, EventKind};
+use crate::sdk::{Proposal, ProposalState};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const DEFAULT_ACTIVE_WINDOW_DAYS: u64 = 30;
+const TOP_COUNT: usize = 10;
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct GrowthPoint {
+    /// Start of the day in nanoseconds
+    pub day: u64,
+    pub joined: u64,
+    pub quit: u64,
+    /// Members at the end of the day
+    pub members: u64,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct DaoAnalytics {
+    pub members: u64,
+    pub active_members: u64,
+    pub active_window_days: u64,
+    pub member_growth: Vec<GrowthPoint>,
+    pub proposals_by_state: Vec<(String, u64)>,
+    /// Proposals whose voting has ended
+    pub closed_proposals: u64,
+    /// Average share of the members voting on a closed proposal
+    pub average_turnout: f64,
+    pub top_proposers: Vec<(Principal, u64)>,
+    pub top_voters: Vec<(Principal, u64)>,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct MemberParticipation {
+    pub principal: Principal,
+    pub proposals: u64,
+    /// Closed proposals the member voted on
+    pub voted: u64,
+    /// Share of the closed proposals the member voted on
+    pub participation: f64,
+    pub last_active_at: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct AnalyticsService {
+    #[serde(default)]
+    built: bool,
+    /// Current members and when they joined
+    #[serde(default)]
+    members: HashMap<Principal, u64>,
+    #[serde(default)]
+    growth: BTreeMap<u64, (u64, u64)>,
+    #[serde(default)]
+    last_active: HashMap<Principal, u64>,
+    #[serde(default)]
+    proposal_states: HashMap<u64, String>,
+    /// Voters of the proposals still open
+    #[serde(default)]
+    open_voters: HashMap<u64, BTreeSet<Principal>>,
+    #[serde(default)]
+    closed_proposals: u64,
+    #[serde(default)]
+    turnout_sum: f64,
+    #[serde(default)]
+    proposers: HashMap<Principal, u64>,
+    #[serde(default)]
+    voters: HashMap<Principal, u64>,
+}
+
+fn state_name(state: &ProposalState) -> String {
+    match state {
+        ProposalState::Failed(_) => String::from("Failed"),
+        state => format!("{:?}", state),
+    }
+}
+
+impl AnalyticsService {
+    /// Whether the statistics were built, they are missing from states written before them
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+
+    pub fn observe(&mut self, event: &Event) {
+        match &event.kind {
+            EventKind::Propose { proposal_id } => {
+                *self.proposers.entry(event.caller).or_default() += 1;
+                self.proposal_states
+                    .insert(*proposal_id, state_name(&ProposalState::Open));
+                self.open_voters.insert(*proposal_id, BTreeSet::new());
+                self.visit(event.caller, event.timestamp);
+            }
+            EventKind::Vote { proposal_id, .. } => {
+                if let Some(voters) = self.open_voters.get_mut(proposal_id) {
+                    voters.insert(event.caller);
+                }
+                self.visit(event.caller, event.timestamp);
+            }
+            EventKind::WithdrawVote { proposal_id } => {
+                if let Some(voters) = self.open_voters.get_mut(proposal_id) {
+                    voters.remove(&event.caller);
+                }
+            }
+            EventKind::ProposalState { proposal_id, state } => {
+                self.proposal_states.insert(*proposal_id, state_name(state));
+                if let Some(voters) = self.open_voters.remove(proposal_id) {
+                    self.close(voters);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Follow a member that was just stored
+    pub fn member_changed(&mut self, member: &MemberItems, now: u64) {
+        if member.is_joined() {
+            self.join(member.principal(), now);
+        } else {
+            self.quit(member.principal(), now);
+        }
+        self.visit(member.principal(), member.last_visit_at());
+    }
+
+    /// Record a visit of a member, see `MemberItems::last_visit_at`
+    fn visit(&mut self, principal: Principal, time: u64) {
+        let last = self.last_active.entry(principal).or_default();
+        *last = (*last).max(time);
+    }
+
+    fn join(&mut self, principal: Principal, time: u64) {
+        if self.members.contains_key(&principal) {
+            return;
+        }
+        self.members.insert(principal, time);
+        self.growth
+            .entry(time / DAY_NANOS * DAY_NANOS)
+            .or_default()
+            .0 += 1;
+        self.visit(principal, time);
+    }
+
+    fn quit(&mut self, principal: Principal, time: u64) {
+        if self.members.remove(&principal).is_some() {
+            self.growth
+                .entry(time / DAY_NANOS * DAY_NANOS)
+                .or_default()
+                .1 += 1;
+        }
+    }
+
+    /// Count the votes of a proposal whose voting has ended
+    fn close(&mut self, voters: BTreeSet<Principal>) {
+        self.closed_proposals += 1;
+        if !self.members.is_empty() {
+            self.turnout_sum += voters.len() as f64 / self.members.len() as f64;
+        }
+        for voter in voters {
+            *self.voters.entry(voter).or_default() += 1;
+        }
+    }
+
+    /// Compute the statistics from the current members and proposals. Quits and withdrawn
+    /// votes before this point are not known, turnout is measured against current members.
+    pub fn rebuild(
+        &mut self,
+        members: impl Iterator<Item = MemberItems>,
+        proposals: impl Iterator<Item = Proposal>,
+    ) {
+        *self = AnalyticsService {
+            built: true,
+            ..Default::default()
+        };
+        for member in members {
+            if member.is_joined() {
+                self.join(member.principal(), member.join_at());
+            }
+            self.visit(member.principal(), member.last_visit_at());
+        }
+        for proposal in proposals {
+            *self.proposers.entry(proposal.proposer).or_default() += 1;
+            self.proposal_states
+                .insert(proposal.id, state_name(&proposal.proposal_state));
+            let voters = proposal.vote_data.iter().map(|vote| vote.0).collect();
+            if proposal.proposal_state == ProposalState::Open {
+                self.open_voters.insert(proposal.id, voters);
+            } else {
+                self.close(voters);
+            }
+        }
+    }
+
+    pub fn analytics(&self, now: u64, active_window_days: Option<u64>) -> DaoAnalytics {
+        let active_window_days = active_window_days.unwrap_or(DEFAULT_ACTIVE_WINDOW_DAYS);
+        let since = now.saturating_sub(active_window_days.saturating_mul(DAY_NANOS));
+        let active_members = self
+            .members
+            .keys()
+            .filter(|principal| {
+                self.last_active
+                    .get(principal)
+                    .is_some_and(|time| *time >= since)
+            })
+            .count() as u64;
+
+        let mut members = 0;
+        let member_growth = self
+            .growth
+            .iter()
+            .map(|(day, (joined, quit))| {
+                members = members + joined - quit;
+                GrowthPoint {
+                    day: *day,
+                    joined: *joined,
+                    quit: *quit,
+                    members,
+                }
+            })
+            .collect();
+
+        let mut proposals_by_state: BTreeMap<String, u64> = BTreeMap::new();
+        for state in self.proposal_states.values() {
+            *proposals_by_state.entry(state.clone()).or_default() += 1;
+        }
+
+        DaoAnalytics {
+            members: self.members.len() as u64,
+            active_members,
+            active_window_days,
+            member_growth,
+            proposals_by_state: proposals_by_state.into_iter().collect(),
+            closed_proposals: self.closed_proposals,
+            average_turnout: if self.closed_proposals == 0 {
+                0.0
+            } else {
+                self.turnout_sum / self.closed_proposals as f64
+            },
+            top_proposers: top(&self.proposers),
+            top_voters: top(&self.voters),
+        }
+    }
+
+    pub fn participation(&self, principal: Principal) -> MemberParticipation {
+        let voted = self.voters.get(&principal).copied().unwrap_or_default();
+        MemberParticipation {
+            principal,
+            proposals: self.proposers.get(&principal).copied().unwrap_or_default(),
+            voted,
+            participation: if self.closed_proposals == 0 {
+                0.0
+            } else {
+                voted as f64 / self.closed_proposals as f64
+            },
+            last_active_at: self.last_active.get(&principal).copied(),
+        }
+    }
+}
+
+fn top(counts: &HashMap<Principal, u64>) -> Vec<(Principal, u64)> {
+    let mut list: Vec<(Principal, u64)> = counts
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(principal, count)| (*principal, *count))
+        .collect();
+    list.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    list.truncate(TOP_COUNT);
+    list
+}
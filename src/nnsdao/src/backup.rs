@@ -0,0 +1,313 @@
+//! Export of the whole state for backups, and import of it into a fresh canister.
+//!
+//! The export is `MAGIC | version: u32 | state | cbor(collections)`, where `state` is the
+//! envelope written by `upgrade::encode_state` and the collections are the ones kept in
+//! `stable`. It is handed out in chunks, the CRC32 of the whole export verifies that an
+//! import received every chunk unchanged.
+//!
+//! Tokens stay with the canister that held them: the treasury and the deposit subaccounts
+//! belong to the exporting canister's principal and are not moved by an import. Queued
+//! payouts are therefore dropped and deposit addresses forgotten when a backup is imported,
+//! the credits already granted are kept.
+
+use crate::dao::MemberItems;
+use crate::disburse::{Disbursement, MAX_DISBURSEMENT_TRIES};
+use crate::events::Event;
+use crate::logger::LogMessageData;
+use crate::sdk::Proposal;
+use crate::upgrade::{self, HEADER_LEN};
+use crate::Data;
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 4] = b"NDBK";
+const BACKUP_VERSION: u32 = 1;
+/// Below the message size limit, with room for the candid encoding
+const CHUNK_SIZE: u64 = 1024 * 1024;
+/// Largest export an import accepts, it is held on the heap while it is received
+const MAX_BACKUP_SIZE: u64 = 1024 * 1024 * 1024;
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub version: u32,
+    pub size: u64,
+    pub chunk_size: u64,
+    pub chunks: u64,
+    pub checksum: u32,
+    pub created_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BackupCollections {
+    members: Vec<(Principal, MemberItems)>,
+    proposals: Vec<(u64, Proposal)>,
+    #[serde(default)]
+    archived_proposals: Vec<(u64, Proposal)>,
+    logs: Vec<LogMessageData>,
+    #[serde(default)]
+    events: Vec<Event>,
+}
+
+/// Export and import in progress, kept on the heap only
+#[derive(Default)]
+pub struct BackupService {
+    export: Option<(BackupInfo, Vec<u8>)>,
+    import: Option<(BackupInfo, Vec<Option<Vec<u8>>>)>,
+}
+
+impl BackupService {
+    /// Take a snapshot of the state, replacing the previous one
+    pub fn export(&mut self, data: &Data) -> Result<BackupInfo, String> {
+        let collections = BackupCollections {
+            members: data.dao.member_list.iter().collect(),
+            proposals: data.dao.basic.proposal_list.iter().collect(),
+            archived_proposals: data.dao.basic.archived_proposals.iter().collect(),
+            logs: data.logger.queue.iter().collect(),
+            events: data.events.events.iter().collect(),
+        };
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&upgrade::encode_state(data)?);
+        bytes.extend_from_slice(
+            &serde_cbor::to_vec(&collections).map_err(|e| format!("Failed to serialize: {}", e))?,
+        );
+
+        let size = bytes.len() as u64;
+        let info = BackupInfo {
+            version: BACKUP_VERSION,
+            size,
+            chunk_size: CHUNK_SIZE,
+            chunks: size.div_ceil(CHUNK_SIZE),
+            checksum: crc32fast::hash(&bytes),
+            created_at: ic::time(),
+        };
+        self.export = Some((info.clone(), bytes));
+        Ok(info)
+    }
+
+    pub fn export_chunk(&self, index: u64) -> Result<Vec<u8>, String> {
+        let (info, bytes) = self
+            .export
+            .as_ref()
+            .ok_or_else(|| String::from("No export in progress"))?;
+        if index >= info.chunks {
+            return Err(format!("Export only has {} chunks", info.chunks));
+        }
+        let start = (index * info.chunk_size) as usize;
+        let end = ((index + 1) * info.chunk_size).min(info.size) as usize;
+        Ok(bytes[start..end].to_vec())
+    }
+
+    /// Prepare to receive the chunks of an export, dropping any unfinished import
+    pub fn begin_import(&mut self, info: BackupInfo) -> Result<(), String> {
+        if info.version != BACKUP_VERSION {
+            return Err(format!("Unknown backup version {}", info.version));
+        }
+        if info.size > MAX_BACKUP_SIZE {
+            return Err(format!("Backup is larger than {} bytes", MAX_BACKUP_SIZE));
+        }
+        if info.chunk_size != CHUNK_SIZE {
+            return Err(format!("Backup chunks must be {} bytes", CHUNK_SIZE));
+        }
+        if info.chunks != info.size.div_ceil(info.chunk_size) {
+            return Err(String::from("Backup size does not match its chunks"));
+        }
+        let chunks = vec![None; info.chunks as usize];
+        self.import = Some((info, chunks));
+        Ok(())
+    }
+
+    pub fn import_chunk(&mut self, index: u64, chunk: Vec<u8>) -> Result<(), String> {
+        let (info, chunks) = self
+            .import
+            .as_mut()
+            .ok_or_else(|| String::from("No import in progress"))?;
+        if index >= info.chunks {
+            return Err(format!("Backup only has {} chunks", info.chunks));
+        }
+        let expected = ((index + 1) * info.chunk_size).min(info.size) - index * info.chunk_size;
+        if chunk.len() as u64 != expected {
+            return Err(format!("Chunk {} must be {} bytes", index, expected));
+        }
+        chunks[index as usize] = Some(chunk);
+        Ok(())
+    }
+
+    /// Verify the received export and build the state it holds. The collections are
+    /// written to stable memory, which must not hold any yet. The payouts that were queued
+    /// in the export are taken out of it and returned, to be settled as failed.
+    pub fn commit_import(&mut self, current: &Data) -> Result<(Data, Vec<Disbursement>), String> {
+        let (info, chunks) = self
+            .import
+            .as_ref()
+            .ok_or_else(|| String::from("No import in progress"))?;
+        if let Some(missing) = chunks.iter().position(Option::is_none) {
+            return Err(format!("Chunk {} has not been received", missing));
+        }
+        let bytes: Vec<u8> = chunks.iter().flatten().flatten().copied().collect();
+        if crc32fast::hash(&bytes) != info.checksum {
+            return Err(String::from(
+                "Checksum does not match, the backup is incomplete",
+            ));
+        }
+        if !current.dao.member_list.is_empty()
+            || !current.dao.basic.proposal_list.is_empty()
+            || !current.dao.basic.archived_proposals.is_empty()
+            || !current.logger.queue.is_empty()
+            || !current.events.events.is_empty()
+        {
+            return Err(String::from(
+                "State can only be imported into a fresh canister",
+            ));
+        }
+
+        let (mut data, collections) = decode(&bytes)?;
+        for (principal, member) in collections.members {
+            data.dao.member_list.insert(principal, member);
+        }
+        for (id, proposal) in collections.proposals {
+            data.dao.basic.proposal_list.insert(id, proposal);
+        }
+        for (id, proposal) in collections.archived_proposals {
+            data.dao.basic.archived_proposals.insert(id, proposal);
+        }
+        for message in collections.logs {
+            data.logger.queue.push(message);
+        }
+        for event in collections.events {
+            data.events.events.push(event);
+        }
+
+        let mut dropped: Vec<Disbursement> = data.disburse.disbursements_queue.drain(..).collect();
+        dropped.append(&mut data.disburse.failed_disbursements);
+        for disbursement in dropped.iter_mut() {
+            disbursement.try_num = MAX_DISBURSEMENT_TRIES;
+        }
+        data.deposit.addresses.clear();
+        data.treasury.subaccounts.clear();
+        self.import = None;
+        Ok((data, dropped))
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<(Data, BackupCollections), String> {
+    if bytes.len() < 8 || &bytes[..4] != MAGIC {
+        return Err(String::from("Not a backup"));
+    }
+    let state = &bytes[8..];
+    let (_, body) = upgrade::split_header(state)?;
+    let data = upgrade::decode_state(state)?;
+    let collections = serde_cbor::from_slice(&state[HEADER_LEN + body.len()..])
+        .map_err(|e| format!("Failed to deserialize: {}", e))?;
+    Ok((data, collections))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::JoinDaoParams;
+    use crate::deposit::DepositPurpose;
+    use crate::disburse::Amount;
+    use crate::stream::StreamArg;
+    use crate::test_utils::{principal, setup};
+    use ic_ledger_types::Subaccount;
+
+    /// Export a state with a member, a deposit address and a queued payout, then start over
+    /// with a fresh one
+    fn exported() -> (BackupInfo, Vec<Vec<u8>>) {
+        setup(principal(1));
+        let mut backup = BackupService::default();
+        let info = ic::with_mut(|data: &mut Data| {
+            data.dao
+                .join(principal(2), JoinDaoParams::default())
+                .unwrap();
+            data.stream.create(
+                StreamArg {
+                    recipient: principal(2),
+                    to_subaccount: None,
+                    total: Amount::NDP(10),
+                    start: Some(0),
+                    cliff: 0,
+                    period: 1,
+                    periods: 1,
+                },
+                None,
+            );
+            data.disburse.disbursements_queue = data.stream.release_due(1);
+            let token = data.dao.dao_config().governance_token().unwrap();
+            let subaccount = Subaccount([1; 32]);
+            data.deposit
+                .add_address(principal(2), DepositPurpose::Stake, &token, subaccount);
+            data.treasury.track_subaccount(subaccount, &token.symbol);
+            backup.export(data).unwrap()
+        });
+        let chunks = (0..info.chunks)
+            .map(|index| backup.export_chunk(index).unwrap())
+            .collect();
+        setup(principal(1));
+        (info, chunks)
+    }
+
+    fn import(info: BackupInfo, chunks: Vec<Vec<u8>>) -> Result<(Data, Vec<Disbursement>), String> {
+        let mut backup = BackupService::default();
+        backup.begin_import(info)?;
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            backup.import_chunk(index as u64, chunk)?;
+        }
+        backup.commit_import(&Data::default())
+    }
+
+    #[test]
+    fn imported_backup_holds_the_exported_state() {
+        let (info, chunks) = exported();
+        assert_eq!(info.chunks, 1);
+        assert_eq!(info.size, chunks[0].len() as u64);
+        let (data, dropped) = import(info, chunks).unwrap();
+        assert!(data.dao.member_list.get(&principal(2)).is_some());
+        assert!(data.stream.get_stream(0).is_ok());
+        // the payout was queued from the funds of the exporting canister
+        assert!(data.disburse.disbursements_queue.is_empty());
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].try_num, MAX_DISBURSEMENT_TRIES);
+        // deposit subaccounts belong to the exporting canister as well
+        assert!(data.deposit.addresses.is_empty());
+        assert!(data.treasury.subaccounts.is_empty());
+    }
+
+    #[test]
+    fn changed_or_missing_chunks_are_refused() {
+        let (info, mut chunks) = exported();
+        chunks[0][20] ^= 1;
+        assert_eq!(
+            import(info.clone(), chunks).unwrap_err(),
+            "Checksum does not match, the backup is incomplete"
+        );
+        assert_eq!(
+            import(info, vec![]).unwrap_err(),
+            "Chunk 0 has not been received"
+        );
+    }
+
+    #[test]
+    fn imports_are_bounded() {
+        let info = BackupInfo {
+            version: BACKUP_VERSION,
+            size: MAX_BACKUP_SIZE + 1,
+            chunk_size: CHUNK_SIZE,
+            chunks: (MAX_BACKUP_SIZE + 1).div_ceil(CHUNK_SIZE),
+            checksum: 0,
+            created_at: 0,
+        };
+        let mut backup = BackupService::default();
+        assert!(backup.begin_import(info.clone()).is_err());
+        let info = BackupInfo {
+            size: 10,
+            chunk_size: 1,
+            chunks: 10,
+            ..info
+        };
+        assert!(backup.begin_import(info).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::disburse::{Amount, Disbursement, MAX_DISBURSEMENT_TRIES};
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_ledger_types::BlockIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum BountyState {
+    /// Waiting for a member to claim it
+    Open,
+    /// A member is working on it
+    Claimed,
+    /// Work has been submitted for review
+    Submitted,
+    /// Work was approved and the reward is being paid out
+    Approved,
+    Paid,
+    Failed(String),
+    Cancelled,
+}
+
+/// Bounty proposed to the DAO, created once the proposal is accepted
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct BountyArg {
+    pub title: String,
+    pub description: String,
+    pub reward: Amount,
+    /// Members allowed to approve the work, moderators when empty
+    pub reviewers: Vec<Principal>,
+    pub deadline: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct BountyEvent {
+    pub timestamp: u64,
+    pub actor: Principal,
+    pub state: BountyState,
+    pub note: Option<String>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct Bounty {
+    pub id: u64,
+    pub title: String,
+    pub description: String,
+    pub reward: Amount,
+    pub reviewers: Vec<Principal>,
+    pub deadline: Option<u64>,
+    pub proposal_id: Option<u64>,
+    pub state: BountyState,
+    pub claimant: Option<Principal>,
+    pub work_links: Vec<String>,
+    pub block_index: Option<BlockIndex>,
+    pub created_at: u64,
+    pub history: Vec<BountyEvent>,
+}
+
+impl Bounty {
+    fn transition(&mut self, actor: Principal, state: BountyState, note: Option<String>) {
+        self.state = state.clone();
+        self.history.push(BountyEvent {
+            timestamp: ic::time(),
+            actor,
+            state,
+            note,
+        });
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct BountyService {
+    #[serde(default)]
+    pub bounties: HashMap<u64, Bounty>,
+    #[serde(default)]
+    pub next_id: u64,
+}
+
+impl BountyService {
+    pub fn validate(arg: &BountyArg) -> Result<(), String> {
+        if arg.title.is_empty() {
+            return Err(String::from("Bounty title must not be empty"));
+        }
+        if arg.reward.is_zero() {
+            return Err(String::from("Bounty reward must not be zero"));
+        }
+        if let Some(deadline) = arg.deadline {
+            if deadline <= ic::time() {
+                return Err(String::from("Bounty deadline must be in the future"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the bounty of an accepted proposal, failing if its deadline passed during the vote
+    pub fn create(
+        &mut self,
+        creator: Principal,
+        arg: BountyArg,
+        proposal_id: Option<u64>,
+    ) -> Result<Bounty, String> {
+        Self::validate(&arg)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut bounty = Bounty {
+            id,
+            title: arg.title,
+            description: arg.description,
+            reward: arg.reward,
+            reviewers: arg.reviewers,
+            deadline: arg.deadline,
+            proposal_id,
+            state: BountyState::Open,
+            claimant: None,
+            work_links: vec![],
+            block_index: None,
+            created_at: ic::time(),
+            history: vec![],
+        };
+        bounty.transition(creator, BountyState::Open, None);
+        self.bounties.insert(id, bounty.clone());
+        Ok(bounty)
+    }
+
+    pub fn get_bounty(&self, id: u64) -> Result<Bounty, String> {
+        self.bounties
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| String::from("Bounty does not exist"))
+    }
+
+    pub fn bounty_list(&self) -> Vec<Bounty> {
+        let mut list: Vec<Bounty> = self.bounties.values().cloned().collect();
+        list.sort_by_key(|bounty| bounty.id);
+        list
+    }
+
+    fn bounty_mut(&mut self, id: u64) -> Result<&mut Bounty, String> {
+        self.bounties
+            .get_mut(&id)
+            .ok_or_else(|| String::from("Bounty does not exist"))
+    }
+
+    pub fn claim(&mut self, caller: Principal, id: u64) -> Result<Bounty, String> {
+        let bounty = self.bounty_mut(id)?;
+        if bounty.state != BountyState::Open {
+            return Err(String::from("Bounty is not open"));
+        }
+        if bounty
+            .deadline
+            .is_some_and(|deadline| ic::time() > deadline)
+        {
+            return Err(String::from("Bounty deadline has passed"));
+        }
+        bounty.claimant = Some(caller);
+        bounty.transition(caller, BountyState::Claimed, None);
+        Ok(bounty.clone())
+    }
+
+    /// The claimant gives the bounty back to other members
+    pub fn unclaim(&mut self, caller: Principal, id: u64) -> Result<Bounty, String> {
+        let bounty = self.bounty_mut(id)?;
+        if bounty.claimant != Some(caller) || bounty.state != BountyState::Claimed {
+            return Err(String::from("Bounty is not claimed by you"));
+        }
+        bounty.claimant = None;
+        bounty.work_links.clear();
+        bounty.transition(caller, BountyState::Open, None);
+        Ok(bounty.clone())
+    }
+
+    pub fn submit(
+        &mut self,
+        caller: Principal,
+        id: u64,
+        work_links: Vec<String>,
+    ) -> Result<Bounty, String> {
+        if work_links.is_empty() {
+            return Err(String::from("Work links must not be empty"));
+        }
+        let bounty = self.bounty_mut(id)?;
+        if bounty.claimant != Some(caller) || bounty.state != BountyState::Claimed {
+            return Err(String::from("Bounty is not claimed by you"));
+        }
+        bounty.work_links = work_links;
+        bounty.transition(caller, BountyState::Submitted, None);
+        Ok(bounty.clone())
+    }
+
+    fn check_reviewer(
+        bounty: &Bounty,
+        caller: Principal,
+        is_moderator: bool,
+    ) -> Result<(), String> {
+        let allowed = if bounty.reviewers.is_empty() {
+            is_moderator
+        } else {
+            bounty.reviewers.contains(&caller)
+        };
+        if !allowed || bounty.claimant == Some(caller) {
+            return Err(String::from("Only reviewers can review this bounty"));
+        }
+        if bounty.state != BountyState::Submitted {
+            return Err(String::from("No work has been submitted"));
+        }
+        Ok(())
+    }
+
+    /// Approve the submitted work, the returned disbursement pays the claimant
+    pub fn approve(
+        &mut self,
+        caller: Principal,
+        id: u64,
+        is_moderator: bool,
+    ) -> Result<(Bounty, Disbursement), String> {
+        let bounty = self.bounty_mut(id)?;
+        Self::check_reviewer(bounty, caller, is_moderator)?;
+        let claimant = bounty
+            .claimant
+            .ok_or_else(|| String::from("Bounty has no claimant"))?;
+        bounty.transition(caller, BountyState::Approved, None);
+        let disbursement = Disbursement {
+            canister: ic::id().to_text(),
+            token_idf: format!("bounty-{}", id),
+            from_subaccount: None,
+            to: claimant,
+            to_subaccount: None,
+            amount: bounty.reward.clone(),
+            try_num: 0,
+            proposal_id: bounty.proposal_id,
+            bounty_id: Some(id),
+            stream_id: None,
+            stream_payment: None,
+            budget_spend: None,
+            token: None,
+        };
+        Ok((bounty.clone(), disbursement))
+    }
+
+    /// Send the work back to the claimant
+    pub fn reject(
+        &mut self,
+        caller: Principal,
+        id: u64,
+        reason: String,
+        is_moderator: bool,
+    ) -> Result<Bounty, String> {
+        let bounty = self.bounty_mut(id)?;
+        Self::check_reviewer(bounty, caller, is_moderator)?;
+        bounty.transition(caller, BountyState::Claimed, Some(reason));
+        Ok(bounty.clone())
+    }
+
+    pub fn cancel(&mut self, caller: Principal, id: u64) -> Result<Bounty, String> {
+        let bounty = self.bounty_mut(id)?;
+        match bounty.state {
+            BountyState::Open | BountyState::Claimed | BountyState::Submitted => {
+                bounty.transition(caller, BountyState::Cancelled, None);
+                Ok(bounty.clone())
+            }
+            _ => Err(String::from("Bounty can no longer be cancelled")),
+        }
+    }
+
+    /// Record the outcome of the reward disbursement
+    pub fn settle(&mut self, disbursement: &Disbursement, result: &Result<BlockIndex, String>) {
+        let bounty = match disbursement
+            .bounty_id
+            .and_then(|id| self.bounties.get_mut(&id))
+        {
+            Some(bounty) => bounty,
+            None => return,
+        };
+        let actor = ic::id();
+        match result {
+            Ok(block) => {
+                bounty.block_index = Some(*block);
+                bounty.transition(actor, BountyState::Paid, Some(format!("block {}", block)));
+            }
+            Err(err) if disbursement.try_num >= MAX_DISBURSEMENT_TRIES => {
+                bounty.transition(actor, BountyState::Failed(err.clone()), None);
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{principal, setup};
+
+    fn bounty_arg(deadline: Option<u64>) -> BountyArg {
+        BountyArg {
+            title: String::from("bounty"),
+            description: String::new(),
+            reward: Amount::Token(String::from("NDP"), 10),
+            reviewers: vec![],
+            deadline,
+        }
+    }
+
+    #[test]
+    fn create_fails_once_the_deadline_has_passed() {
+        setup(principal(1));
+        let mut service = BountyService::default();
+        let passed = bounty_arg(Some(ic::time() - 1));
+        assert!(service.create(principal(1), passed, Some(1)).is_err());
+        assert!(service.bounty_list().is_empty());
+        let open = bounty_arg(Some(ic::time() + 60_000_000_000));
+        assert!(service.create(principal(1), open, Some(1)).is_ok());
+    }
+}
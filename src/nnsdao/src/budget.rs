@@ -0,0 +1,274 @@
+use crate::disburse::{Amount, Disbursement, MAX_DISBURSEMENT_TRIES};
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_ledger_types::{BlockIndex, Subaccount};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Budget proposed to the DAO for a working group
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct BudgetArg {
+    pub group: String,
+    /// Members allowed to spend the budget without a vote
+    pub spenders: Vec<Principal>,
+    /// Token and amount allocated for the whole period
+    pub allocation: Amount,
+    /// Start of the period, defaults to the time the proposal is executed
+    pub start: Option<u64>,
+    pub end: u64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct SpendArg {
+    pub budget_id: u64,
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: u64,
+    pub reason: String,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct BudgetSpend {
+    pub id: u64,
+    pub spender: Principal,
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: u64,
+    pub reason: String,
+    pub timestamp: u64,
+    pub block_index: Option<BlockIndex>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct Budget {
+    pub id: u64,
+    pub group: String,
+    pub spenders: Vec<Principal>,
+    pub allocation: Amount,
+    pub start: u64,
+    pub end: u64,
+    pub proposal_id: Option<u64>,
+    /// Amount committed by spends that have not failed
+    pub spent: u64,
+    pub spends: Vec<BudgetSpend>,
+}
+
+impl Budget {
+    pub fn remaining(&self) -> u64 {
+        self.allocation.value().saturating_sub(self.spent)
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct BudgetService {
+    #[serde(default)]
+    pub budgets: HashMap<u64, Budget>,
+    #[serde(default)]
+    pub next_id: u64,
+}
+
+impl BudgetService {
+    pub fn validate(arg: &BudgetArg) -> Result<(), String> {
+        if arg.group.is_empty() {
+            return Err(String::from("Budget group must not be empty"));
+        }
+        if arg.spenders.is_empty() {
+            return Err(String::from("Budget must have at least one spender"));
+        }
+        if arg.allocation.is_zero() {
+            return Err(String::from("Budget allocation must not be zero"));
+        }
+        if arg.end <= arg.start.unwrap_or_else(ic::time) {
+            return Err(String::from("Budget period must end after it starts"));
+        }
+        Ok(())
+    }
+
+    pub fn create(&mut self, arg: BudgetArg, proposal_id: Option<u64>) -> Budget {
+        let id = self.next_id;
+        self.next_id += 1;
+        let budget = Budget {
+            id,
+            group: arg.group,
+            spenders: arg.spenders,
+            allocation: arg.allocation,
+            start: arg.start.unwrap_or_else(ic::time),
+            end: arg.end,
+            proposal_id,
+            spent: 0,
+            spends: vec![],
+        };
+        self.budgets.insert(id, budget.clone());
+        budget
+    }
+
+    pub fn get_budget(&self, id: u64) -> Result<Budget, String> {
+        self.budgets
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| String::from("Budget does not exist"))
+    }
+
+    pub fn budget_list(&self, group: Option<String>) -> Vec<Budget> {
+        let mut list: Vec<Budget> = self
+            .budgets
+            .values()
+            .filter(|budget| group.as_ref().is_none_or(|group| budget.group == *group))
+            .cloned()
+            .collect();
+        list.sort_by_key(|budget| budget.id);
+        list
+    }
+
+    /// Commit part of the budget, the returned disbursement pays the recipient
+    pub fn spend(&mut self, caller: Principal, arg: SpendArg) -> Result<Disbursement, String> {
+        let budget = self
+            .budgets
+            .get_mut(&arg.budget_id)
+            .ok_or_else(|| String::from("Budget does not exist"))?;
+        if !budget.spenders.contains(&caller) {
+            return Err(String::from("Only spenders of the budget can spend it"));
+        }
+        let now = ic::time();
+        if now < budget.start || now > budget.end {
+            return Err(String::from("Budget period is not active"));
+        }
+        if arg.amount == 0 {
+            return Err(String::from("Spend amount must not be zero"));
+        }
+        if arg.amount > budget.remaining() {
+            return Err(format!("Only {} left in the budget", budget.remaining()));
+        }
+        let spend_id = budget.spends.len() as u64;
+        budget.spent += arg.amount;
+        budget.spends.push(BudgetSpend {
+            id: spend_id,
+            spender: caller,
+            to: arg.to,
+            to_subaccount: arg.to_subaccount,
+            amount: arg.amount,
+            reason: arg.reason,
+            timestamp: now,
+            block_index: None,
+            error: None,
+        });
+        Ok(Disbursement {
+            canister: ic::id().to_text(),
+            token_idf: format!("budget-{}-{}", budget.id, spend_id),
+            from_subaccount: None,
+            to: arg.to,
+            to_subaccount: arg.to_subaccount,
+            amount: budget.allocation.with_value(arg.amount),
+            try_num: 0,
+            proposal_id: budget.proposal_id,
+            bounty_id: None,
+            stream_id: None,
+            stream_payment: None,
+            budget_spend: Some((budget.id, spend_id)),
+            token: None,
+        })
+    }
+
+    /// Record the outcome of a spend, failed spends go back to the budget
+    pub fn settle(&mut self, disbursement: &Disbursement, result: &Result<BlockIndex, String>) {
+        let (budget_id, spend_id) = match disbursement.budget_spend {
+            Some(ids) => ids,
+            None => return,
+        };
+        let budget = match self.budgets.get_mut(&budget_id) {
+            Some(budget) => budget,
+            None => return,
+        };
+        let spend = match budget.spends.get_mut(spend_id as usize) {
+            Some(spend) => spend,
+            None => return,
+        };
+        match result {
+            Ok(block) => {
+                spend.block_index = Some(*block);
+                spend.error = None;
+            }
+            Err(err) => {
+                spend.error = Some(err.clone());
+                if disbursement.try_num >= MAX_DISBURSEMENT_TRIES {
+                    budget.spent -= spend.amount;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{principal, setup};
+
+    fn service() -> BudgetService {
+        let mut service = BudgetService::default();
+        service.create(
+            BudgetArg {
+                group: String::from("group"),
+                spenders: vec![principal(1)],
+                allocation: Amount::NDP(100),
+                start: Some(0),
+                end: u64::MAX,
+            },
+            None,
+        );
+        service
+    }
+
+    fn spend(amount: u64) -> SpendArg {
+        SpendArg {
+            budget_id: 0,
+            to: principal(2),
+            to_subaccount: None,
+            amount,
+            reason: String::from("reason"),
+        }
+    }
+
+    #[test]
+    fn spends_are_limited_to_spenders_and_the_allocation() {
+        setup(principal(1));
+        let mut service = service();
+        assert!(service.spend(principal(2), spend(10)).is_err());
+        assert!(service.spend(principal(1), spend(0)).is_err());
+        let disbursement = service.spend(principal(1), spend(60)).unwrap();
+        assert_eq!(disbursement.amount.value(), 60);
+        assert_eq!(disbursement.budget_spend, Some((0, 0)));
+        assert_eq!(
+            service.spend(principal(1), spend(50)).unwrap_err(),
+            "Only 40 left in the budget"
+        );
+        assert_eq!(service.get_budget(0).unwrap().remaining(), 40);
+    }
+
+    #[test]
+    fn failed_spends_return_to_the_budget_after_the_last_try() {
+        setup(principal(1));
+        let mut service = service();
+        let mut disbursement = service.spend(principal(1), spend(60)).unwrap();
+        let error = Err(String::from("failed"));
+        service.settle(&disbursement, &error);
+        assert_eq!(service.get_budget(0).unwrap().spent, 60);
+
+        disbursement.try_num = MAX_DISBURSEMENT_TRIES;
+        service.settle(&disbursement, &error);
+        let budget = service.get_budget(0).unwrap();
+        assert_eq!(budget.spent, 0);
+        assert_eq!(budget.spends[0].error, Some(String::from("failed")));
+    }
+
+    #[test]
+    fn paid_spends_keep_their_block() {
+        setup(principal(1));
+        let mut service = service();
+        let disbursement = service.spend(principal(1), spend(60)).unwrap();
+        service.settle(&disbursement, &Ok(3));
+        let budget = service.get_budget(0).unwrap();
+        assert_eq!(budget.spent, 60);
+        assert_eq!(budget.spends[0].block_index, Some(3));
+    }
+}
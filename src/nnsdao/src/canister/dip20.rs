@@ -2,7 +2,7 @@ use candid::{Nat, Principal};
 use ic_cdk::api::call::CallResult;
 use ic_cdk::export::candid::{self, CandidType, Deserialize};
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum TxError {
     InsufficientAllowance,
     InsufficientBalance,
@@ -15,21 +15,21 @@ pub enum TxError {
     AmountTooSmall,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum Result {
     Ok(candid::Nat),
     Err(TxError),
 }
 
 #[derive(CandidType, Deserialize)]
-struct Metadata {
-    fee: candid::Nat,
-    decimals: u8,
-    owner: candid::Principal,
-    logo: String,
-    name: String,
-    totalSupply: candid::Nat,
-    symbol: String,
+pub struct Metadata {
+    pub fee: candid::Nat,
+    pub decimals: u8,
+    pub owner: candid::Principal,
+    pub logo: String,
+    pub name: String,
+    pub totalSupply: candid::Nat,
+    pub symbol: String,
 }
 
 #[derive(CandidType, Deserialize)]
@@ -11,6 +11,10 @@ impl CanisterExtClient {
         CanisterExtClient { id: canister_id }
     }
 
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub async fn transfer(
         &self,
         arg: ext::TransferRequest,
@@ -0,0 +1,164 @@
+use candid::{Int, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::candid::{self, CandidType, Deserialize};
+
+pub type Subaccount = Vec<u8>;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferResult {
+    Ok(Nat),
+    Err(TransferError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveResult {
+    Ok(Nat),
+    Err(ApproveError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromResult {
+    Ok(Nat),
+    Err(TransferFromError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+pub struct Service(candid::Principal);
+
+impl Service {
+    pub fn new(principal: Principal) -> Self {
+        Service(principal)
+    }
+    pub async fn icrc1_balance_of(&self, arg0: Account) -> CallResult<(Nat,)> {
+        ic_cdk::call(self.0, "icrc1_balance_of", (arg0,)).await
+    }
+    pub async fn icrc1_decimals(&self) -> CallResult<(u8,)> {
+        ic_cdk::call(self.0, "icrc1_decimals", ()).await
+    }
+    pub async fn icrc1_fee(&self) -> CallResult<(Nat,)> {
+        ic_cdk::call(self.0, "icrc1_fee", ()).await
+    }
+    pub async fn icrc1_metadata(&self) -> CallResult<(Vec<(String, MetadataValue)>,)> {
+        ic_cdk::call(self.0, "icrc1_metadata", ()).await
+    }
+    pub async fn icrc1_name(&self) -> CallResult<(String,)> {
+        ic_cdk::call(self.0, "icrc1_name", ()).await
+    }
+    pub async fn icrc1_symbol(&self) -> CallResult<(String,)> {
+        ic_cdk::call(self.0, "icrc1_symbol", ()).await
+    }
+    pub async fn icrc1_total_supply(&self) -> CallResult<(Nat,)> {
+        ic_cdk::call(self.0, "icrc1_total_supply", ()).await
+    }
+    pub async fn icrc1_transfer(&self, arg0: TransferArg) -> CallResult<(TransferResult,)> {
+        ic_cdk::call(self.0, "icrc1_transfer", (arg0,)).await
+    }
+    pub async fn icrc2_approve(&self, arg0: ApproveArgs) -> CallResult<(ApproveResult,)> {
+        ic_cdk::call(self.0, "icrc2_approve", (arg0,)).await
+    }
+    pub async fn icrc2_transfer_from(
+        &self,
+        arg0: TransferFromArgs,
+    ) -> CallResult<(TransferFromResult,)> {
+        ic_cdk::call(self.0, "icrc2_transfer_from", (arg0,)).await
+    }
+    pub async fn icrc2_allowance(&self, arg0: AllowanceArgs) -> CallResult<(Allowance,)> {
+        ic_cdk::call(self.0, "icrc2_allowance", (arg0,)).await
+    }
+}
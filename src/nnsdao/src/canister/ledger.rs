@@ -1,4 +1,6 @@
+use crate::canister::dip20;
 use crate::canister::ext_client::CanisterExtClient;
+use crate::canister::icrc1::{self, Account};
 use crate::canister::standard_ext::{self as ext, TransferRequest, TransferResponse, User};
 use ic_cdk::export::candid::Principal;
 
@@ -8,6 +10,8 @@ use ic_ledger_types::{
 };
 use std::convert::TryFrom;
 
+use candid::Nat;
+
 pub async fn icp_balance(
     user: Principal,
     user_subaccount: Option<Subaccount>,
@@ -56,6 +60,37 @@ pub async fn ndp_balance(
 ) -> Result<u128, String> {
     let ledger = CanisterExtClient::new(String::from("vgqnj-miaaa-aaaal-qaapa-cai"));
 
+    ext_balance(&ledger, user, user_subaccount).await
+}
+
+// Transfer funds on nns ledger
+pub async fn ndp_transfer(
+    from: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Principal,
+    to_subaccount: Option<Subaccount>,
+    amount: u128,
+    memo: Vec<u8>,
+) -> Result<BlockIndex, String> {
+    let ledger = CanisterExtClient::new(String::from("vgqnj-miaaa-aaaal-qaapa-cai"));
+
+    ext_transfer(
+        &ledger,
+        from,
+        from_subaccount,
+        to,
+        to_subaccount,
+        amount,
+        memo,
+    )
+    .await
+}
+
+pub async fn ext_balance(
+    ledger: &CanisterExtClient,
+    user: Principal,
+    user_subaccount: Option<Subaccount>,
+) -> Result<u128, String> {
     let aid = AccountIdentifier::new(&user, &user_subaccount.unwrap_or(DEFAULT_SUBACCOUNT));
 
     let arg = ext::BalanceRequest {
@@ -63,14 +98,20 @@ pub async fn ndp_balance(
         user: User::address(aid.to_string().to_lowercase()),
     };
 
-    match ledger.balance(arg).await.unwrap().0 {
+    match ledger
+        .balance(arg)
+        .await
+        .map_err(|e| format!("failed to call ledger: {:?}", e))?
+        .0
+    {
         ext::BalanceResponse::ok(balance) => Ok(balance),
         ext::BalanceResponse::err(err) => Err(format!("{:#?}", &err)),
     }
 }
 
-// Transfer funds on nns ledger
-pub async fn ndp_transfer(
+// Transfer funds on an ext fungible token
+pub async fn ext_transfer(
+    ledger: &CanisterExtClient,
     from: Principal,
     from_subaccount: Option<Subaccount>,
     to: Principal,
@@ -78,8 +119,6 @@ pub async fn ndp_transfer(
     amount: u128,
     memo: Vec<u8>,
 ) -> Result<BlockIndex, String> {
-    let ledger = CanisterExtClient::new(String::from("vgqnj-miaaa-aaaal-qaapa-cai"));
-
     let from = AccountIdentifier::new(&from, &from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT));
     let to = AccountIdentifier::new(&to, &to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT));
 
@@ -87,16 +126,78 @@ pub async fn ndp_transfer(
         from: User::address(from.to_string().to_lowercase()),
         subaccount: Some(from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT).0.to_vec()),
         to: User::address(to.to_string().to_lowercase()),
-        token: String::from("vgqnj-miaaa-aaaal-qaapa-cai"),
+        token: ledger.id().to_string(),
         amount,
         notify: false,
         memo,
     };
 
-    match ledger.transfer(arg).await.unwrap().0 {
-        TransferResponse::ok(block) => Ok(u64::try_from(block).unwrap()),
-        TransferResponse::err(err) => {
-            return Err(format!("ledger transfer error {:?}", err));
+    match ledger
+        .transfer(arg)
+        .await
+        .map_err(|e| format!("failed to call ledger: {:?}", e))?
+        .0
+    {
+        TransferResponse::ok(block) => {
+            u64::try_from(block).map_err(|e| format!("invalid block index {:?}", e))
+        }
+        TransferResponse::err(err) => Err(format!("ledger transfer error {:?}", err)),
+    }
+}
+
+// Transfer funds on a dip20 token canister
+pub async fn dip20_transfer(
+    canister: Principal,
+    to: Principal,
+    amount: Nat,
+) -> Result<BlockIndex, String> {
+    let token = dip20::Service::new(canister);
+
+    match token
+        .transfer(to, amount)
+        .await
+        .map_err(|e| format!("failed to call token: {:?}", e))?
+        .0
+    {
+        dip20::Result::Ok(block) => {
+            u64::try_from(&block.0).map_err(|e| format!("invalid block index {:?}", e))
+        }
+        dip20::Result::Err(err) => Err(format!("token transfer error {:?}", err)),
+    }
+}
+
+// Transfer funds on an icrc1 ledger
+pub async fn icrc1_transfer(
+    canister: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Principal,
+    to_subaccount: Option<Subaccount>,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+) -> Result<BlockIndex, String> {
+    let token = icrc1::Service::new(canister);
+
+    let arg = icrc1::TransferArg {
+        from_subaccount: from_subaccount.map(|s| s.0.to_vec()),
+        to: Account {
+            owner: to,
+            subaccount: to_subaccount.map(|s| s.0.to_vec()),
+        },
+        amount,
+        fee: None,
+        memo,
+        created_at_time: None,
+    };
+
+    match token
+        .icrc1_transfer(arg)
+        .await
+        .map_err(|e| format!("failed to call ledger: {:?}", e))?
+        .0
+    {
+        icrc1::TransferResult::Ok(block) => {
+            u64::try_from(&block.0).map_err(|e| format!("invalid block index {:?}", e))
         }
+        icrc1::TransferResult::Err(err) => Err(format!("ledger transfer error {:?}", err)),
     }
 }
@@ -1,4 +1,6 @@
 pub mod dip20;
 pub mod ext_client;
+pub mod icrc1;
 pub mod ledger;
 pub mod standard_ext;
+pub mod token_ledger;
@@ -0,0 +1,551 @@
+//! A common interface over the token standards the DAO can hold.
+
+use crate::canister::dip20;
+use crate::canister::ext_client::CanisterExtClient;
+use crate::canister::icrc1;
+use crate::canister::ledger;
+use async_trait::async_trait;
+use candid::{CandidType, Nat, Principal};
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, BlockIndex, Memo, Subaccount, Tokens, TransferArgs,
+    DEFAULT_FEE, DEFAULT_SUBACCOUNT,
+};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Named like the `Amount` variants of the disbursements
+#[allow(clippy::upper_case_acronyms)]
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum TokenStandard {
+    DIP20,
+    EXT,
+    ICRC1,
+    ICP,
+}
+
+/// A token registered with the DAO
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct TokenConfig {
+    pub symbol: String,
+    pub canister: Principal,
+    pub standard: TokenStandard,
+    /// Decimals to use for ledgers that do not expose them
+    #[serde(default)]
+    pub decimals: Option<u8>,
+}
+
+/// Decimals and transfer fee of a token ledger
+#[derive(CandidType, Clone, Copy, Debug)]
+pub struct TokenUnits {
+    pub decimals: u8,
+    pub fee: u128,
+}
+
+impl TokenUnits {
+    /// Base units of an amount given in whole tokens
+    pub fn whole(&self, tokens: u64) -> Result<u128, String> {
+        10u128
+            .checked_pow(self.decimals as u32)
+            .and_then(|unit| unit.checked_mul(tokens as u128))
+            .ok_or_else(|| format!("{} tokens overflow the ledger amount", tokens))
+    }
+
+    /// What a recipient receives of a payout once the ledger fee is taken
+    pub fn after_fee(&self, amount: u128) -> u128 {
+        amount.saturating_sub(self.fee)
+    }
+}
+
+impl TokenConfig {
+    pub fn ledger(&self) -> Box<dyn TokenLedger> {
+        #[cfg(test)]
+        if let Some(ledger) = test_ledger::TestLedger::installed() {
+            return Box::new(ledger);
+        }
+        match self.standard {
+            TokenStandard::DIP20 => Box::new(dip20::Service::new(self.canister)),
+            TokenStandard::EXT => Box::new(CanisterExtClient::new(self.canister.to_text())),
+            TokenStandard::ICRC1 => Box::new(icrc1::Service::new(self.canister)),
+            TokenStandard::ICP => Box::new(IcpLedger(self.canister)),
+        }
+    }
+
+    pub async fn units(&self) -> Result<TokenUnits, String> {
+        let ledger = self.ledger();
+        let decimals = match self.decimals {
+            Some(decimals) => decimals,
+            None => ledger.decimals().await?,
+        };
+        Ok(TokenUnits {
+            decimals,
+            fee: ledger.fee().await?,
+        })
+    }
+
+    /// Whether balances can be held on subaccounts of the DAO canister
+    pub fn supports_subaccounts(&self) -> bool {
+        self.standard != TokenStandard::DIP20
+    }
+}
+
+/// Operations the DAO needs from a token ledger, amounts are in the ledger's base unit
+#[async_trait(?Send)]
+pub trait TokenLedger {
+    async fn balance_of(
+        &self,
+        owner: Principal,
+        subaccount: Option<Subaccount>,
+    ) -> Result<u128, String>;
+
+    async fn transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Principal,
+        to_subaccount: Option<Subaccount>,
+        amount: u128,
+        memo: u64,
+    ) -> Result<BlockIndex, String>;
+
+    /// Move funds approved by `from` to the DAO canister
+    async fn transfer_from(&self, from: Principal, amount: u128) -> Result<BlockIndex, String>;
+
+    /// What `owner` approved the DAO canister to move with `transfer_from`
+    async fn allowance(&self, owner: Principal) -> Result<u128, String>;
+
+    async fn fee(&self) -> Result<u128, String>;
+
+    async fn decimals(&self) -> Result<u8, String>;
+}
+
+fn nat_to_u64(value: Nat) -> Result<u64, String> {
+    u64::try_from(&value.0).map_err(|e| format!("invalid number {:?}", e))
+}
+
+fn nat_to_u128(value: Nat) -> Result<u128, String> {
+    u128::try_from(&value.0).map_err(|e| format!("invalid number {:?}", e))
+}
+
+#[async_trait(?Send)]
+impl TokenLedger for dip20::Service {
+    async fn balance_of(
+        &self,
+        owner: Principal,
+        _subaccount: Option<Subaccount>,
+    ) -> Result<u128, String> {
+        let (balance,) = self
+            .balanceOf(owner)
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?;
+        nat_to_u128(balance)
+    }
+
+    async fn transfer(
+        &self,
+        _from_subaccount: Option<Subaccount>,
+        to: Principal,
+        _to_subaccount: Option<Subaccount>,
+        amount: u128,
+        _memo: u64,
+    ) -> Result<BlockIndex, String> {
+        match dip20::Service::transfer(self, to, amount.into())
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?
+            .0
+        {
+            dip20::Result::Ok(block) => nat_to_u64(block),
+            dip20::Result::Err(err) => Err(format!("token transfer error {:?}", err)),
+        }
+    }
+
+    async fn transfer_from(&self, from: Principal, amount: u128) -> Result<BlockIndex, String> {
+        match self
+            .transferFrom(from, ic_cdk::api::id(), amount.into())
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?
+            .0
+        {
+            dip20::Result::Ok(block) => nat_to_u64(block),
+            dip20::Result::Err(err) => Err(format!("token transfer error {:?}", err)),
+        }
+    }
+
+    async fn allowance(&self, owner: Principal) -> Result<u128, String> {
+        let (allowance,) = dip20::Service::allowance(self, owner, ic_cdk::api::id())
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?;
+        nat_to_u128(allowance)
+    }
+
+    async fn fee(&self) -> Result<u128, String> {
+        let (metadata,) = self
+            .getMetadata()
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?;
+        nat_to_u128(metadata.fee)
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        let (decimals,) = dip20::Service::decimals(self)
+            .await
+            .map_err(|e| format!("failed to call token: {:?}", e))?;
+        Ok(decimals)
+    }
+}
+
+#[async_trait(?Send)]
+impl TokenLedger for CanisterExtClient {
+    async fn balance_of(
+        &self,
+        owner: Principal,
+        subaccount: Option<Subaccount>,
+    ) -> Result<u128, String> {
+        ledger::ext_balance(self, owner, subaccount).await
+    }
+
+    async fn transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Principal,
+        to_subaccount: Option<Subaccount>,
+        amount: u128,
+        memo: u64,
+    ) -> Result<BlockIndex, String> {
+        ledger::ext_transfer(
+            self,
+            ic_cdk::api::id(),
+            from_subaccount,
+            to,
+            to_subaccount,
+            amount,
+            memo.to_be_bytes().to_vec(),
+        )
+        .await
+    }
+
+    async fn transfer_from(&self, _from: Principal, _amount: u128) -> Result<BlockIndex, String> {
+        Err(String::from("EXT tokens do not support transfer_from"))
+    }
+
+    async fn allowance(&self, _owner: Principal) -> Result<u128, String> {
+        Err(String::from("EXT tokens do not support allowances"))
+    }
+
+    async fn fee(&self) -> Result<u128, String> {
+        Ok(0)
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        Err(String::from("EXT tokens do not expose decimals"))
+    }
+}
+
+#[async_trait(?Send)]
+impl TokenLedger for icrc1::Service {
+    async fn balance_of(
+        &self,
+        owner: Principal,
+        subaccount: Option<Subaccount>,
+    ) -> Result<u128, String> {
+        let (balance,) = self
+            .icrc1_balance_of(icrc1::Account {
+                owner,
+                subaccount: subaccount.map(|s| s.0.to_vec()),
+            })
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+        nat_to_u128(balance)
+    }
+
+    async fn transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Principal,
+        to_subaccount: Option<Subaccount>,
+        amount: u128,
+        memo: u64,
+    ) -> Result<BlockIndex, String> {
+        let arg = icrc1::TransferArg {
+            from_subaccount: from_subaccount.map(|s| s.0.to_vec()),
+            to: icrc1::Account {
+                owner: to,
+                subaccount: to_subaccount.map(|s| s.0.to_vec()),
+            },
+            amount: amount.into(),
+            fee: None,
+            memo: Some(memo.to_be_bytes().to_vec()),
+            created_at_time: None,
+        };
+        match self
+            .icrc1_transfer(arg)
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?
+            .0
+        {
+            icrc1::TransferResult::Ok(block) => nat_to_u64(block),
+            icrc1::TransferResult::Err(err) => Err(format!("ledger transfer error {:?}", err)),
+        }
+    }
+
+    async fn transfer_from(&self, from: Principal, amount: u128) -> Result<BlockIndex, String> {
+        let arg = icrc1::TransferFromArgs {
+            spender_subaccount: None,
+            from: icrc1::Account {
+                owner: from,
+                subaccount: None,
+            },
+            to: icrc1::Account {
+                owner: ic_cdk::api::id(),
+                subaccount: None,
+            },
+            amount: amount.into(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        match self
+            .icrc2_transfer_from(arg)
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?
+            .0
+        {
+            icrc1::TransferFromResult::Ok(block) => nat_to_u64(block),
+            icrc1::TransferFromResult::Err(err) => Err(format!("ledger transfer error {:?}", err)),
+        }
+    }
+
+    async fn allowance(&self, owner: Principal) -> Result<u128, String> {
+        let arg = icrc1::AllowanceArgs {
+            account: icrc1::Account {
+                owner,
+                subaccount: None,
+            },
+            spender: icrc1::Account {
+                owner: ic_cdk::api::id(),
+                subaccount: None,
+            },
+        };
+        let (allowance,) = self
+            .icrc2_allowance(arg)
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+        nat_to_u128(allowance.allowance)
+    }
+
+    async fn fee(&self) -> Result<u128, String> {
+        let (fee,) = self
+            .icrc1_fee()
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+        nat_to_u128(fee)
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        let (decimals,) = self
+            .icrc1_decimals()
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?;
+        Ok(decimals)
+    }
+}
+
+/// The ICP ledger, addressed through account identifiers
+pub struct IcpLedger(pub Principal);
+
+#[async_trait(?Send)]
+impl TokenLedger for IcpLedger {
+    async fn balance_of(
+        &self,
+        owner: Principal,
+        subaccount: Option<Subaccount>,
+    ) -> Result<u128, String> {
+        let arg = AccountBalanceArgs {
+            account: AccountIdentifier::new(&owner, &subaccount.unwrap_or(DEFAULT_SUBACCOUNT)),
+        };
+        ic_ledger_types::account_balance(self.0, arg)
+            .await
+            .map(|tokens| tokens.e8s() as u128)
+            .map_err(|e| format!("failed to call ledger: {:?}", e))
+    }
+
+    async fn transfer(
+        &self,
+        from_subaccount: Option<Subaccount>,
+        to: Principal,
+        to_subaccount: Option<Subaccount>,
+        amount: u128,
+        memo: u64,
+    ) -> Result<BlockIndex, String> {
+        let amount = u64::try_from(amount).map_err(|e| format!("invalid amount {:?}", e))?;
+        let arg = TransferArgs {
+            memo: Memo(memo),
+            amount: Tokens::from_e8s(amount),
+            fee: DEFAULT_FEE,
+            from_subaccount,
+            to: AccountIdentifier::new(&to, &to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT)),
+            created_at_time: None,
+        };
+        ic_ledger_types::transfer(self.0, arg)
+            .await
+            .map_err(|e| format!("failed to call ledger: {:?}", e))?
+            .map_err(|e| format!("ledger transfer error {:?}", e))
+    }
+
+    async fn transfer_from(&self, _from: Principal, _amount: u128) -> Result<BlockIndex, String> {
+        Err(String::from(
+            "The ICP ledger does not support transfer_from",
+        ))
+    }
+
+    async fn allowance(&self, _owner: Principal) -> Result<u128, String> {
+        Err(String::from("The ICP ledger does not support allowances"))
+    }
+
+    async fn fee(&self) -> Result<u128, String> {
+        Ok(DEFAULT_FEE.e8s() as u128)
+    }
+
+    async fn decimals(&self) -> Result<u8, String> {
+        Ok(8)
+    }
+}
+
+/// An in-memory ledger for the tests, its calls can be held to interleave concurrent updates
+#[cfg(test)]
+pub mod test_ledger {
+    use super::TokenLedger;
+    use async_trait::async_trait;
+    use candid::Principal;
+    use ic_kit::ic;
+    use ic_ledger_types::{BlockIndex, Subaccount};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    #[derive(Default)]
+    struct State {
+        installed: bool,
+        paused: bool,
+        failing: bool,
+        balances: HashMap<Principal, u128>,
+        transfers: Vec<(Principal, Principal, u128)>,
+    }
+
+    thread_local! {
+        static STATE: RefCell<State> = RefCell::new(State::default());
+    }
+
+    /// Route every `TokenConfig::ledger` of this thread to the test ledger
+    pub fn install() {
+        STATE.with(|state| {
+            *state.borrow_mut() = State {
+                installed: true,
+                ..State::default()
+            }
+        });
+    }
+
+    pub fn set_balance(owner: Principal, amount: u128) {
+        STATE.with(|state| state.borrow_mut().balances.insert(owner, amount));
+    }
+
+    /// Hold transfers until the ledger is resumed
+    pub fn pause(paused: bool) {
+        STATE.with(|state| state.borrow_mut().paused = paused);
+    }
+
+    /// Make transfers fail
+    pub fn fail(failing: bool) {
+        STATE.with(|state| state.borrow_mut().failing = failing);
+    }
+
+    /// Completed transfers as `(from, to, amount)`
+    pub fn transfers() -> Vec<(Principal, Principal, u128)> {
+        STATE.with(|state| state.borrow().transfers.clone())
+    }
+
+    pub struct TestLedger;
+
+    impl TestLedger {
+        pub fn installed() -> Option<TestLedger> {
+            STATE
+                .with(|state| state.borrow().installed)
+                .then_some(TestLedger)
+        }
+
+        async fn move_funds(
+            &self,
+            from: Principal,
+            to: Principal,
+            amount: u128,
+        ) -> Result<BlockIndex, String> {
+            Pause.await;
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                if state.failing {
+                    return Err(String::from("ledger transfer error"));
+                }
+                let balance = state.balances.entry(from).or_default();
+                *balance = balance
+                    .checked_sub(amount)
+                    .ok_or_else(|| String::from("insufficient funds"))?;
+                *state.balances.entry(to).or_default() += amount;
+                state.transfers.push((from, to, amount));
+                Ok(state.transfers.len() as BlockIndex)
+            })
+        }
+    }
+
+    /// Pending while the ledger is paused
+    struct Pause;
+
+    impl Future for Pause {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+            match STATE.with(|state| state.borrow().paused) {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl TokenLedger for TestLedger {
+        async fn balance_of(
+            &self,
+            owner: Principal,
+            _subaccount: Option<Subaccount>,
+        ) -> Result<u128, String> {
+            Ok(STATE.with(|state| state.borrow().balances.get(&owner).copied().unwrap_or(0)))
+        }
+
+        async fn transfer(
+            &self,
+            _from_subaccount: Option<Subaccount>,
+            to: Principal,
+            _to_subaccount: Option<Subaccount>,
+            amount: u128,
+            _memo: u64,
+        ) -> Result<BlockIndex, String> {
+            self.move_funds(ic::id(), to, amount).await
+        }
+
+        async fn transfer_from(&self, from: Principal, amount: u128) -> Result<BlockIndex, String> {
+            self.move_funds(from, ic::id(), amount).await
+        }
+
+        async fn allowance(&self, owner: Principal) -> Result<u128, String> {
+            self.balance_of(owner, None).await
+        }
+
+        async fn fee(&self) -> Result<u128, String> {
+            Ok(0)
+        }
+
+        async fn decimals(&self) -> Result<u8, String> {
+            Ok(0)
+        }
+    }
+}
@@ -0,0 +1,761 @@
+//! Certified data of the canister.
+//!
+//! The certified data is the root hash of an IC hash tree:
+//!
+//! ```text
+//! dao_info              leaf: sha256(candid(DaoInfo))
+//! events                leaf: tip hash of the event log
+//! members / principal   leaf: sha256(candid(MemberItems))
+//! proposals / id        leaf: sha256(candid(Proposal)), ids are 8 bytes big endian
+//! ```
+//!
+//! The leaves of a section are kept in a red-black tree whose nodes hold the hash of their
+//! subtree, as in the `RbTree` of ic-certified-map: a write rehashes the path to one leaf and
+//! a witness prunes every subtree without a revealed key.
+//!
+//! Certified queries return the value with the certificate and a witness, the CBOR encoded
+//! hash tree with everything else pruned. A client verifies the certificate, checks that
+//! the witness reconstructs to the certified data and that the leaf is the hash of the
+//! candid encoding of the value.
+
+use crate::dao::{DaoInfo, MemberItems};
+use crate::sdk::Proposal;
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+
+type Hash = [u8; 32];
+
+const DAO_INFO: &[u8] = b"dao_info";
+const EVENTS: &[u8] = b"events";
+const MEMBERS: &[u8] = b"members";
+const PROPOSALS: &[u8] = b"proposals";
+
+/// A value with the proof that it is part of the certified data
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct Certified<T> {
+    pub data: T,
+    /// Only available in queries
+    pub certificate: Option<Vec<u8>>,
+    pub witness: Vec<u8>,
+}
+
+/// Which parts of the tree a witness reveals
+enum Reveal<'a> {
+    Nothing,
+    Everything,
+    Keys(&'a [Vec<u8>]),
+}
+
+impl Reveal<'_> {
+    fn includes(&self, key: &[u8]) -> bool {
+        match self {
+            Reveal::Nothing => false,
+            Reveal::Everything => true,
+            Reveal::Keys(keys) => keys.iter().any(|item| item == key),
+        }
+    }
+
+    /// Whether a key strictly between the bounds is revealed
+    fn touches(&self, low: Option<&[u8]>, high: Option<&[u8]>) -> bool {
+        match self {
+            Reveal::Nothing => false,
+            Reveal::Everything => true,
+            Reveal::Keys(keys) => keys.iter().any(|key| {
+                low.is_none_or(|low| key.as_slice() > low)
+                    && high.is_none_or(|high| key.as_slice() < high)
+            }),
+        }
+    }
+}
+
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+impl HashTree {
+    fn reconstruct(&self) -> Hash {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => domain_hash(
+                "ic-hashtree-fork",
+                &[&left.reconstruct(), &right.reconstruct()],
+            ),
+            HashTree::Labeled(label, tree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &tree.reconstruct()])
+            }
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+            HashTree::Pruned(hash) => *hash,
+        }
+    }
+
+    /// Pruned copy of the tree, it reconstructs to the same hash
+    fn pruned(self) -> HashTree {
+        match self {
+            HashTree::Pruned(_) => self,
+            tree => HashTree::Pruned(tree.reconstruct()),
+        }
+    }
+
+    fn is_pruned(&self) -> bool {
+        matches!(self, HashTree::Pruned(_))
+    }
+
+    /// CBOR encoding with the self-describing tag, as expected by the IC agents
+    fn to_cbor(&self) -> Vec<u8> {
+        let mut bytes = vec![0xd9, 0xd9, 0xf7];
+        self.write_cbor(&mut bytes);
+        bytes
+    }
+
+    fn write_cbor(&self, bytes: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => {
+                write_cbor_head(bytes, 4, 1);
+                write_cbor_head(bytes, 0, 0);
+            }
+            HashTree::Fork(left, right) => {
+                write_cbor_head(bytes, 4, 3);
+                write_cbor_head(bytes, 0, 1);
+                left.write_cbor(bytes);
+                right.write_cbor(bytes);
+            }
+            HashTree::Labeled(label, tree) => {
+                write_cbor_head(bytes, 4, 3);
+                write_cbor_head(bytes, 0, 2);
+                write_cbor_bytes(bytes, label);
+                tree.write_cbor(bytes);
+            }
+            HashTree::Leaf(value) => {
+                write_cbor_head(bytes, 4, 2);
+                write_cbor_head(bytes, 0, 3);
+                write_cbor_bytes(bytes, value);
+            }
+            HashTree::Pruned(hash) => {
+                write_cbor_head(bytes, 4, 2);
+                write_cbor_head(bytes, 0, 4);
+                write_cbor_bytes(bytes, hash);
+            }
+        }
+    }
+}
+
+fn write_cbor_head(bytes: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        bytes.push(major | value as u8);
+    } else if value <= u8::MAX as u64 {
+        bytes.extend_from_slice(&[major | 24, value as u8]);
+    } else if value <= u16::MAX as u64 {
+        bytes.push(major | 25);
+        bytes.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        bytes.push(major | 26);
+        bytes.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        bytes.push(major | 27);
+        bytes.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_cbor_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    write_cbor_head(bytes, 2, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn member_key(principal: Principal) -> Vec<u8> {
+    principal.as_slice().to_vec()
+}
+
+fn value_hash<T: CandidType>(value: &T) -> Hash {
+    let bytes = candid::encode_one(value).expect("Failed to encode certified value.");
+    Sha256::digest(bytes).into()
+}
+
+/// Balanced fork tree over labeled subtrees sorted by label, pruned forks are merged
+fn fork_tree(mut nodes: Vec<HashTree>) -> HashTree {
+    if nodes.is_empty() {
+        return HashTree::Empty;
+    }
+    while nodes.len() > 1 {
+        let mut next = Vec::with_capacity(nodes.len() / 2 + 1);
+        let mut iter = nodes.into_iter();
+        while let Some(left) = iter.next() {
+            match iter.next() {
+                Some(right) => {
+                    let pruned = left.is_pruned() && right.is_pruned();
+                    let fork = HashTree::Fork(Box::new(left), Box::new(right));
+                    next.push(if pruned { fork.pruned() } else { fork });
+                }
+                None => next.push(left),
+            }
+        }
+        nodes = next;
+    }
+    nodes.remove(0)
+}
+
+fn fork_hash(left: &Hash, right: &Hash) -> Hash {
+    domain_hash("ic-hashtree-fork", &[left, right])
+}
+
+fn labeled_leaf_hash(key: &[u8], value: &Hash) -> Hash {
+    let leaf = domain_hash("ic-hashtree-leaf", &[value]);
+    domain_hash("ic-hashtree-labeled", &[key, &leaf])
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    key: Vec<u8>,
+    value: Hash,
+    red: bool,
+    left: Option<Box<Node>>,
+    right: Option<Box<Node>>,
+    /// Hash of the labeled leaf of this node
+    data_hash: Hash,
+    /// Hash of `left`, the leaf and `right` forked together, see `witness`
+    subtree_hash: Hash,
+}
+
+impl Node {
+    fn new(key: Vec<u8>, value: Hash) -> Box<Node> {
+        let data_hash = labeled_leaf_hash(&key, &value);
+        Box::new(Node {
+            key,
+            value,
+            red: true,
+            left: None,
+            right: None,
+            data_hash,
+            subtree_hash: data_hash,
+        })
+    }
+
+    fn update_hash(&mut self) {
+        self.subtree_hash = match (&self.left, &self.right) {
+            (None, None) => self.data_hash,
+            (Some(left), None) => fork_hash(&left.subtree_hash, &self.data_hash),
+            (None, Some(right)) => fork_hash(&self.data_hash, &right.subtree_hash),
+            (Some(left), Some(right)) => fork_hash(
+                &left.subtree_hash,
+                &fork_hash(&self.data_hash, &right.subtree_hash),
+            ),
+        };
+    }
+}
+
+fn is_red(node: &Option<Box<Node>>) -> bool {
+    node.as_ref().is_some_and(|node| node.red)
+}
+
+fn rotate_left(mut node: Box<Node>) -> Box<Node> {
+    let mut right = node.right.take().expect("Rotated node has no right child.");
+    node.right = right.left.take();
+    node.update_hash();
+    right.red = node.red;
+    node.red = true;
+    right.left = Some(node);
+    right.update_hash();
+    right
+}
+
+fn rotate_right(mut node: Box<Node>) -> Box<Node> {
+    let mut left = node.left.take().expect("Rotated node has no left child.");
+    node.left = left.right.take();
+    node.update_hash();
+    left.red = node.red;
+    node.red = true;
+    left.right = Some(node);
+    left.update_hash();
+    left
+}
+
+fn flip_colors(node: &mut Node) {
+    node.red = !node.red;
+    for child in node.left.iter_mut().chain(node.right.iter_mut()) {
+        child.red = !child.red;
+    }
+}
+
+/// Left-leaning red-black insertion, the hashes are updated on the way back up
+fn insert_node(node: Option<Box<Node>>, key: Vec<u8>, value: Hash) -> Box<Node> {
+    let mut node = match node {
+        Some(node) => node,
+        None => return Node::new(key, value),
+    };
+    match key.as_slice().cmp(&node.key) {
+        Ordering::Less => node.left = Some(insert_node(node.left.take(), key, value)),
+        Ordering::Greater => node.right = Some(insert_node(node.right.take(), key, value)),
+        Ordering::Equal => {
+            node.value = value;
+            node.data_hash = labeled_leaf_hash(&node.key, &value);
+        }
+    }
+    if is_red(&node.right) && !is_red(&node.left) {
+        node = rotate_left(node);
+    }
+    if is_red(&node.left) && node.left.as_ref().is_some_and(|left| is_red(&left.left)) {
+        node = rotate_right(node);
+    }
+    if is_red(&node.left) && is_red(&node.right) {
+        flip_colors(&mut node);
+    }
+    node.update_hash();
+    node
+}
+
+/// Hash tree of a subtree whose keys lie strictly between the bounds
+fn witness(
+    node: &Option<Box<Node>>,
+    reveal: &Reveal,
+    low: Option<&[u8]>,
+    high: Option<&[u8]>,
+) -> Option<HashTree> {
+    let node = node.as_ref()?;
+    if !reveal.touches(low, high) {
+        return Some(HashTree::Pruned(node.subtree_hash));
+    }
+    let left = witness(&node.left, reveal, low, Some(&node.key));
+    let right = witness(&node.right, reveal, Some(&node.key), high);
+    let data = if reveal.includes(&node.key) {
+        HashTree::Labeled(
+            node.key.clone(),
+            Box::new(HashTree::Leaf(node.value.to_vec())),
+        )
+    } else {
+        HashTree::Pruned(node.data_hash)
+    };
+    Some(match (left, right) {
+        (None, None) => data,
+        (Some(left), None) => HashTree::Fork(Box::new(left), Box::new(data)),
+        (None, Some(right)) => HashTree::Fork(Box::new(data), Box::new(right)),
+        (Some(left), Some(right)) => HashTree::Fork(
+            Box::new(left),
+            Box::new(HashTree::Fork(Box::new(data), Box::new(right))),
+        ),
+    })
+}
+
+/// Leaves of one section of the certified tree, see the module documentation
+#[derive(Clone, Default, Debug)]
+struct LeafTree {
+    root: Option<Box<Node>>,
+}
+
+impl LeafTree {
+    fn insert(&mut self, key: Vec<u8>, value: Hash) {
+        let mut root = insert_node(self.root.take(), key, value);
+        root.red = false;
+        self.root = Some(root);
+    }
+
+    fn witness(&self, reveal: &Reveal) -> HashTree {
+        witness(&self.root, reveal, None, None).unwrap_or(HashTree::Empty)
+    }
+}
+
+/// A node as written to the upgrade blob, in pre-order with the hashes it had
+#[derive(Serialize, Deserialize)]
+struct StoredNode {
+    key: Vec<u8>,
+    value: Hash,
+    red: bool,
+    has_left: bool,
+    has_right: bool,
+    data_hash: Hash,
+    subtree_hash: Hash,
+}
+
+fn store_nodes(node: &Option<Box<Node>>, stored: &mut Vec<StoredNode>) {
+    if let Some(node) = node {
+        stored.push(StoredNode {
+            key: node.key.clone(),
+            value: node.value,
+            red: node.red,
+            has_left: node.left.is_some(),
+            has_right: node.right.is_some(),
+            data_hash: node.data_hash,
+            subtree_hash: node.subtree_hash,
+        });
+        store_nodes(&node.left, stored);
+        store_nodes(&node.right, stored);
+    }
+}
+
+fn load_node(stored: &mut impl Iterator<Item = StoredNode>) -> Result<Box<Node>, String> {
+    let node = stored
+        .next()
+        .ok_or_else(|| String::from("Certified tree is truncated"))?;
+    let left = match node.has_left {
+        true => Some(load_node(stored)?),
+        false => None,
+    };
+    let right = match node.has_right {
+        true => Some(load_node(stored)?),
+        false => None,
+    };
+    Ok(Box::new(Node {
+        key: node.key,
+        value: node.value,
+        red: node.red,
+        left,
+        right,
+        data_hash: node.data_hash,
+        subtree_hash: node.subtree_hash,
+    }))
+}
+
+/// Kept with its hashes so that nothing is hashed again after an upgrade
+impl Serialize for LeafTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut stored = vec![];
+        store_nodes(&self.root, &mut stored);
+        stored.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LeafTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = Vec::<StoredNode>::deserialize(deserializer)?;
+        if stored.is_empty() {
+            return Ok(LeafTree::default());
+        }
+        let mut stored = stored.into_iter();
+        let root = load_node(&mut stored).map_err(de::Error::custom)?;
+        if stored.next().is_some() {
+            return Err(de::Error::custom("Certified tree has unreachable nodes"));
+        }
+        Ok(LeafTree { root: Some(root) })
+    }
+}
+
+/// Leaf hashes of the certified tree, the values themselves are stored elsewhere
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct CertifiedService {
+    #[serde(default)]
+    dao_info: Option<Hash>,
+    #[serde(default)]
+    events: Option<Vec<u8>>,
+    #[serde(default)]
+    members: LeafTree,
+    #[serde(default)]
+    proposals: LeafTree,
+}
+
+impl CertifiedService {
+    /// Whether the tree was built, it is missing from states written before it existed
+    pub fn is_built(&self) -> bool {
+        self.dao_info.is_some()
+    }
+
+    pub fn set_dao_info(&mut self, info: &DaoInfo) {
+        self.dao_info = Some(value_hash(info));
+    }
+
+    pub fn set_event_tip(&mut self, tip: Option<Vec<u8>>) {
+        self.events = tip;
+    }
+
+    pub fn set_member(&mut self, principal: Principal, member: &MemberItems) {
+        self.members
+            .insert(member_key(principal), value_hash(member));
+    }
+
+    pub fn set_proposal(&mut self, proposal: &Proposal) {
+        self.proposals
+            .insert(proposal.id.to_be_bytes().to_vec(), value_hash(proposal));
+    }
+
+    /// Set the root hash as the certified data of the canister
+    pub fn certify(&self) {
+        ic::set_certified_data(&self.tree(&[]).reconstruct());
+    }
+
+    /// Build the tree revealing the given paths, `[section]` reveals the whole section
+    fn tree(&self, paths: &[(&[u8], Reveal)]) -> HashTree {
+        let reveal = |section: &[u8]| {
+            paths
+                .iter()
+                .find(|(name, _)| *name == section)
+                .map(|(_, reveal)| reveal)
+                .unwrap_or(&Reveal::Nothing)
+        };
+        let leaf = |section: &[u8], value: Vec<u8>| {
+            let node = HashTree::Labeled(section.to_vec(), Box::new(HashTree::Leaf(value)));
+            match reveal(section) {
+                Reveal::Nothing => node.pruned(),
+                _ => node,
+            }
+        };
+        let section = |name: &[u8], leaves: &LeafTree| {
+            let reveal = reveal(name);
+            let node = HashTree::Labeled(name.to_vec(), Box::new(leaves.witness(reveal)));
+            match reveal {
+                Reveal::Nothing => node.pruned(),
+                _ => node,
+            }
+        };
+        fork_tree(vec![
+            leaf(DAO_INFO, self.dao_info.unwrap_or_default().to_vec()),
+            leaf(EVENTS, self.events.clone().unwrap_or_default()),
+            section(MEMBERS, &self.members),
+            section(PROPOSALS, &self.proposals),
+        ])
+    }
+
+    fn certified<T>(&self, data: T, paths: &[(&[u8], Reveal)]) -> Certified<T> {
+        Certified {
+            data,
+            certificate: ic::data_certificate(),
+            witness: self.tree(paths).to_cbor(),
+        }
+    }
+
+    pub fn dao_info(&self, info: DaoInfo) -> Certified<DaoInfo> {
+        self.certified(info, &[(DAO_INFO, Reveal::Everything)])
+    }
+
+    pub fn event_tip<T>(&self, data: T) -> Certified<T> {
+        self.certified(data, &[(EVENTS, Reveal::Everything)])
+    }
+
+    pub fn members(&self, members: Vec<MemberItems>) -> Certified<Vec<MemberItems>> {
+        self.certified(members, &[(MEMBERS, Reveal::Everything)])
+    }
+
+    pub fn proposal(&self, proposal: Proposal) -> Certified<Proposal> {
+        let key = [proposal.id.to_be_bytes().to_vec()];
+        self.certified(proposal, &[(PROPOSALS, Reveal::Keys(&key))])
+    }
+
+    pub fn proposals(&self, proposals: Vec<Proposal>) -> Certified<Vec<Proposal>> {
+        let keys: Vec<Vec<u8>> = proposals
+            .iter()
+            .map(|proposal| proposal.id.to_be_bytes().to_vec())
+            .collect();
+        self.certified(proposals, &[(PROPOSALS, Reveal::Keys(&keys))])
+    }
+
+    /// Hash every certified value again
+    pub fn rebuild(
+        &mut self,
+        info: &DaoInfo,
+        event_tip: Option<Vec<u8>>,
+        members: impl Iterator<Item = (Principal, MemberItems)>,
+        proposals: impl Iterator<Item = Proposal>,
+    ) {
+        *self = CertifiedService::default();
+        self.set_dao_info(info);
+        self.set_event_tip(event_tip);
+        for (principal, member) in members {
+            self.set_member(principal, &member);
+        }
+        for proposal in proposals {
+            self.set_proposal(&proposal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::JoinDaoParams;
+    use crate::test_utils::{principal, setup};
+    use crate::Data;
+
+    fn labeled(label: &str, tree: HashTree) -> HashTree {
+        HashTree::Labeled(label.as_bytes().to_vec(), Box::new(tree))
+    }
+
+    fn leaf(value: &str) -> HashTree {
+        HashTree::Leaf(value.as_bytes().to_vec())
+    }
+
+    fn fork(left: HashTree, right: HashTree) -> HashTree {
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// The example tree of the interface specification
+    fn example() -> HashTree {
+        fork(
+            fork(
+                labeled(
+                    "a",
+                    fork(
+                        fork(labeled("x", leaf("hello")), HashTree::Empty),
+                        labeled("y", leaf("world")),
+                    ),
+                ),
+                labeled("b", leaf("good")),
+            ),
+            fork(labeled("c", HashTree::Empty), labeled("d", leaf("morning"))),
+        )
+    }
+
+    #[test]
+    fn root_hash_matches_the_specification() {
+        assert_eq!(
+            hex(&example().reconstruct()),
+            "eb5c5b2195e62d996b84c9bcc8259d19a83786a2f59e0878cec84c811f669aa0"
+        );
+        assert_eq!(example().pruned().reconstruct(), example().reconstruct());
+    }
+
+    #[test]
+    fn cbor_encoding_matches_the_specification() {
+        assert_eq!(hex(&HashTree::Empty.to_cbor()), "d9d9f78100");
+        assert_eq!(
+            hex(&fork(labeled("a", leaf("b")), HashTree::Empty).to_cbor()),
+            "d9d9f7830183024161820341628100"
+        );
+        let pruned = HashTree::Pruned([7; 32]).to_cbor();
+        assert_eq!(hex(&pruned[..6]), "d9d9f7820458");
+        assert_eq!(pruned[6], 32);
+        let mut head = vec![];
+        write_cbor_head(&mut head, 2, 300);
+        assert_eq!(hex(&head), "59012c");
+    }
+
+    #[test]
+    fn witnesses_reconstruct_to_the_certified_root() {
+        setup(principal(1));
+        let members: Vec<MemberItems> = ic::with_mut(|data: &mut Data| {
+            (2..5)
+                .map(|n| {
+                    data.dao
+                        .join(principal(n), JoinDaoParams::default())
+                        .unwrap()
+                })
+                .collect()
+        });
+        let mut service = CertifiedService::default();
+        service.rebuild(
+            &DaoInfo::default(),
+            Some(vec![1; 32]),
+            members
+                .iter()
+                .map(|member| (member.principal(), member.clone())),
+            std::iter::empty(),
+        );
+        let root = service.tree(&[]).reconstruct();
+        let key = [member_key(principal(3))];
+        for paths in [
+            vec![(DAO_INFO, Reveal::Everything)],
+            vec![(EVENTS, Reveal::Everything)],
+            vec![(MEMBERS, Reveal::Everything)],
+            vec![(MEMBERS, Reveal::Keys(&key))],
+        ] {
+            assert_eq!(service.tree(&paths).reconstruct(), root);
+        }
+
+        let witness = service.tree(&[(MEMBERS, Reveal::Keys(&key))]).to_cbor();
+        let contains = |value: &[u8]| witness.windows(value.len()).any(|part| part == value);
+        assert!(contains(&value_hash(&members[1])));
+        assert!(!contains(&value_hash(&members[0])));
+
+        service.set_member(principal(3), &members[0]);
+        assert_ne!(service.tree(&[]).reconstruct(), root);
+    }
+
+    /// Hash of a subtree computed from scratch
+    fn full_hash(node: &Option<Box<Node>>) -> Option<Hash> {
+        let node = node.as_ref()?;
+        let data = labeled_leaf_hash(&node.key, &node.value);
+        Some(match (full_hash(&node.left), full_hash(&node.right)) {
+            (None, None) => data,
+            (Some(left), None) => fork_hash(&left, &data),
+            (None, Some(right)) => fork_hash(&data, &right),
+            (Some(left), Some(right)) => fork_hash(&left, &fork_hash(&data, &right)),
+        })
+    }
+
+    /// Black nodes on every path down, red nodes have no red children
+    fn black_height(node: &Option<Box<Node>>) -> usize {
+        let node = match node {
+            Some(node) => node,
+            None => return 0,
+        };
+        assert!(!node.red || !(is_red(&node.left) || is_red(&node.right)));
+        let height = black_height(&node.left);
+        assert_eq!(height, black_height(&node.right));
+        height + !node.red as usize
+    }
+
+    fn root_hash(tree: &LeafTree) -> Hash {
+        match &tree.root {
+            Some(root) => root.subtree_hash,
+            None => HashTree::Empty.reconstruct(),
+        }
+    }
+
+    fn tree_size(tree: &HashTree) -> usize {
+        match tree {
+            HashTree::Fork(left, right) => 1 + tree_size(left) + tree_size(right),
+            HashTree::Labeled(_, tree) => 1 + tree_size(tree),
+            _ => 1,
+        }
+    }
+
+    #[test]
+    fn leaf_tree_stays_balanced_and_hashed() {
+        let mut tree = LeafTree::default();
+        assert_eq!(
+            tree.witness(&Reveal::Everything).reconstruct(),
+            root_hash(&tree)
+        );
+        for n in 0..500u64 {
+            tree.insert((n * 7919 % 500).to_be_bytes().to_vec(), [n as u8; 32]);
+        }
+        for n in (0..500u64).step_by(50) {
+            tree.insert(n.to_be_bytes().to_vec(), [0; 32]);
+        }
+        assert_eq!(full_hash(&tree.root), Some(root_hash(&tree)));
+        assert!(black_height(&tree.root) <= 9);
+
+        let key = [42u64.to_be_bytes().to_vec()];
+        let witness = tree.witness(&Reveal::Keys(&key));
+        assert_eq!(witness.reconstruct(), root_hash(&tree));
+        assert!(tree_size(&witness) < 60);
+        let all = tree.witness(&Reveal::Everything);
+        assert_eq!(all.reconstruct(), root_hash(&tree));
+        assert!(tree_size(&all) > 1000);
+    }
+
+    #[test]
+    fn leaf_tree_is_stored_with_its_hashes() {
+        let mut tree = LeafTree::default();
+        for n in 0..20u64 {
+            tree.insert(n.to_be_bytes().to_vec(), [n as u8; 32]);
+        }
+        let bytes = serde_cbor::to_vec(&tree).unwrap();
+        let loaded: LeafTree = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(format!("{:?}", loaded), format!("{:?}", tree));
+        assert_eq!(root_hash(&loaded), root_hash(&tree));
+
+        let empty = serde_cbor::to_vec(&LeafTree::default()).unwrap();
+        assert!(serde_cbor::from_slice::<LeafTree>(&empty)
+            .unwrap()
+            .root
+            .is_none());
+    }
+}
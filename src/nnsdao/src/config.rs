@@ -0,0 +1,212 @@
+use crate::canister::token_ledger::{TokenConfig, TokenStandard};
+use crate::proposal::{ProposalKind, ProposalRule};
+use candid::{CandidType, Principal};
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// NDP is both an EXT and a DIP20 token on the same canister
+pub const NDP_CANISTER: &str = "vgqnj-miaaa-aaaal-qaapa-cai";
+
+/// What happens when a proposer edits a proposal whose voting has already started
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum EditPolicy {
+    /// Proposals can no longer be edited once voting has started
+    #[default]
+    Locked,
+    /// Editing is allowed, all votes are refunded and voting starts over
+    ResetVotes,
+}
+
+/// How new members are admitted to the DAO
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum AdmissionMode {
+    /// Anyone can join
+    #[default]
+    Open,
+    /// Members are admitted by an accepted membership proposal
+    Proposal,
+    /// No new members are admitted
+    Closed,
+}
+
+/// Who pays the ledger fee when escrowed tokens are given back
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Default, Debug, PartialEq, Eq)]
+pub enum FeePolicy {
+    /// The fee is taken from the amount paid to the recipient
+    #[default]
+    Deduct,
+    /// The recipient receives the full amount and the treasury pays the fee
+    Absorb,
+}
+
+/// Governance rules of the DAO
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct DaoConfig {
+    #[serde(default)]
+    pub edit_policy: EditPolicy,
+    /// Overrides of the built-in voting rules per proposal kind
+    #[serde(default)]
+    pub proposal_rules: HashMap<ProposalKind, ProposalRule>,
+    /// Whole tokens escrowed from the proposer when submitting a proposal
+    #[serde(default = "default_proposal_deposit")]
+    pub proposal_deposit: u64,
+    #[serde(default)]
+    pub admission_mode: AdmissionMode,
+    /// Tokens the DAO can receive and pay out
+    #[serde(default = "default_tokens")]
+    pub tokens: Vec<TokenConfig>,
+    /// Symbol of the registered token used for proposal deposits and voting escrow
+    #[serde(default = "default_governance_token")]
+    pub governance_token: String,
+    #[serde(default)]
+    pub fee_policy: FeePolicy,
+    /// Finalized proposals are archived this long after voting ended, 0 keeps them all
+    #[serde(default = "default_archive_after_seconds")]
+    pub archive_after_seconds: u64,
+    #[serde(default)]
+    pub reputation: ReputationConfig,
+}
+
+/// Reputation points awarded to members, see `reputation`
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ReputationConfig {
+    pub propose_points: u64,
+    /// Awarded once per proposal voted on
+    pub vote_points: u64,
+    /// Awarded to the proposer when the proposal is accepted
+    pub accepted_points: u64,
+    /// Awarded to the author of a comment for every like
+    pub like_points: u64,
+    /// Days for a score to halve, 0 keeps points forever
+    pub half_life_days: u64,
+    /// Extra voting weight in percent per point of reputation, 0 disables the multiplier
+    pub vote_bonus_percent_per_point: u64,
+    pub max_bonus_percent: u64,
+}
+
+impl Default for ReputationConfig {
+    fn default() -> Self {
+        Self {
+            propose_points: 5,
+            vote_points: 1,
+            accepted_points: 10,
+            like_points: 1,
+            half_life_days: 90,
+            vote_bonus_percent_per_point: 0,
+            max_bonus_percent: 50,
+        }
+    }
+}
+
+fn default_archive_after_seconds() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_proposal_deposit() -> u64 {
+    1
+}
+
+fn default_tokens() -> Vec<TokenConfig> {
+    let ndp = Principal::from_text(NDP_CANISTER).unwrap();
+    vec![
+        TokenConfig {
+            symbol: String::from("NDP"),
+            canister: ndp,
+            standard: TokenStandard::DIP20,
+            decimals: None,
+        },
+        TokenConfig {
+            symbol: String::from("NDP-EXT"),
+            canister: ndp,
+            standard: TokenStandard::EXT,
+            decimals: Some(8),
+        },
+        TokenConfig {
+            symbol: String::from("ICP"),
+            canister: MAINNET_LEDGER_CANISTER_ID,
+            standard: TokenStandard::ICP,
+            decimals: None,
+        },
+    ]
+}
+
+fn default_governance_token() -> String {
+    String::from("NDP")
+}
+
+impl Default for DaoConfig {
+    fn default() -> Self {
+        Self {
+            edit_policy: Default::default(),
+            proposal_rules: Default::default(),
+            proposal_deposit: default_proposal_deposit(),
+            admission_mode: Default::default(),
+            tokens: default_tokens(),
+            governance_token: default_governance_token(),
+            fee_policy: Default::default(),
+            archive_after_seconds: default_archive_after_seconds(),
+            reputation: Default::default(),
+        }
+    }
+}
+
+impl DaoConfig {
+    pub fn proposal_rule(&self, kind: ProposalKind) -> ProposalRule {
+        self.proposal_rules
+            .get(&kind)
+            .cloned()
+            .unwrap_or_else(|| ProposalRule::default_for(kind))
+    }
+
+    pub fn token(&self, symbol: &str) -> Result<TokenConfig, String> {
+        self.tokens
+            .iter()
+            .find(|token| token.symbol == symbol)
+            .cloned()
+            .ok_or_else(|| format!("Token {} is not registered", symbol))
+    }
+
+    pub fn governance_token(&self) -> Result<TokenConfig, String> {
+        self.token(&self.governance_token)
+    }
+
+    /// Checks applied to a whole config, the same a parameter change proposal has to pass
+    pub fn validate(&self) -> Result<(), String> {
+        for (index, token) in self.tokens.iter().enumerate() {
+            if token.symbol.is_empty() {
+                return Err(String::from("Token symbol must not be empty"));
+            }
+            if self.tokens[..index]
+                .iter()
+                .any(|item| item.symbol == token.symbol)
+            {
+                return Err(format!("Token {} is registered twice", token.symbol));
+            }
+        }
+        self.governance_token()?;
+        for rule in self.proposal_rules.values() {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Convert a `proposal_deposit` kept in base units of the governance token to whole
+    /// tokens, rounding up so that a deposit is never dropped. Tokens without configured
+    /// decimals are taken to have 8, like every built-in token.
+    pub fn deposit_units_to_whole(&mut self) {
+        let decimals = self
+            .governance_token()
+            .ok()
+            .and_then(|token| token.decimals)
+            .unwrap_or(8);
+        let unit = 10u64.saturating_pow(decimals as u32);
+        self.proposal_deposit = self.proposal_deposit.div_ceil(unit);
+    }
+
+    /// The token escrowed before the registry existed
+    pub fn legacy_escrow_token() -> TokenConfig {
+        default_tokens().remove(0)
+    }
+}
@@ -1,16 +1,36 @@
+use crate::bounty::BountyService;
+use crate::budget::BudgetService;
+use crate::canister::token_ledger::{TokenConfig, TokenUnits};
+use crate::config::AdmissionMode;
+use crate::config::{DaoConfig, EditPolicy, FeePolicy};
+use crate::disburse::{Amount, Disbursement, MAX_DISBURSEMENT_TRIES};
+use crate::events::EventKind;
+use crate::logger::LogLevel;
+use crate::proposal::{MembershipAction, MembershipChange, ParameterChange, ProposalPayload};
+use crate::reputation;
 use crate::sdk::{
-    ChangeProposalStateArg, DaoBasic, DaoCustomFn, Proposal, ProposalArg, ProposalState, Votes,
-    VotesArg,
+    ChangeProposalStateArg, DaoBasic, DaoCustomFn, EditProposalArg, Equities, Proposal,
+    ProposalArg, ProposalState, Votes,
 };
-use crate::{canister::dip20, Data};
+use crate::stable::{StableMap, MEMBERS};
+use crate::stream::{StreamService, StreamState};
+use crate::treasury::{FlowDirection, FlowKind, PayoutReport};
+use crate::{tools, Data};
 use async_trait::async_trait;
 use candid::{CandidType, Deserialize};
 use ic_cdk::export::Principal;
 use ic_kit::ic;
+use ic_ledger_types::BlockIndex;
 
-use num_bigint::ToBigUint;
 use serde::Serialize;
-use std::{collections::HashMap, option::Option::Some};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::option::Option::Some;
+
+/// Bounds the work of a single heartbeat
+const MAX_ARCHIVED_PER_BEAT: usize = 100;
+/// The archive pass scans every hot proposal, so it runs at most this often
+const ARCHIVE_INTERVAL_SECONDS: u64 = 60 * 60;
 
 #[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
 pub struct CustomDao {}
@@ -23,14 +43,17 @@ pub struct UserVoteArgs {
 #[async_trait]
 impl DaoCustomFn for CustomDao {
     async fn is_member(&self, member: Principal) -> Result<bool, String> {
-        let data = ic::get_mut::<Data>();
-        data.dao.is_member(member)
+        ic::with(|data: &Data| data.dao.is_member(member))
     }
 
     async fn handle_proposal(&self) -> Result<(), String> {
         // heartbeat
         Ok(())
     }
+
+    fn proposal_changed(&self, proposal: &Proposal) {
+        tools::certify(|certified| certified.set_proposal(proposal));
+    }
 }
 
 // / error ic post_upgrade not support
@@ -64,6 +87,22 @@ pub struct MemberItems {
     join_at: u64,
     last_visit_at: u64,
 }
+
+impl MemberItems {
+    pub fn principal(&self) -> Principal {
+        self.principal
+    }
+    pub fn is_joined(&self) -> bool {
+        self.status_code == 1
+    }
+    pub fn join_at(&self) -> u64 {
+        self.join_at
+    }
+    pub fn last_visit_at(&self) -> u64 {
+        self.last_visit_at
+    }
+}
+
 #[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
 pub struct JoinDaoParams {
     pub nickname: String,
@@ -92,9 +131,9 @@ impl Default for DaoInfo {
             avatar: Default::default(),
             tags: Default::default(),
             intro: Default::default(),
-            canister_id: ic_cdk::id().to_text(),
+            canister_id: ic::id().to_text(),
             option: Default::default(),
-            created_at: ic_cdk::api::time(),
+            created_at: ic::time(),
         }
     }
 }
@@ -105,15 +144,42 @@ pub struct VoteArg {
     pub ndp_count: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Default, Clone, Debug)]
+#[derive(Serialize, Deserialize, Default, Debug)]
 pub struct DaoService {
-    member_list: HashMap<Principal, MemberItems>,
+    /// Kept in stable memory, see `stable`
+    #[serde(skip)]
+    pub(crate) member_list: StableMap<Principal, MemberItems, MEMBERS>,
     // proposer_list: Vec<ProposerListItem>,
     // votes_list: Vec<UserVoteArgs>,
     info: DaoInfo,
     pub basic: DaoBasic<CustomDao>,
     pub pending_proposal: Vec<u64>,
     pub proposal_log: Vec<(u64, Result<String, String>)>,
+    #[serde(default)]
+    moderators: Vec<Principal>,
+    #[serde(default)]
+    config: DaoConfig,
+    /// Deposit escrowed for each proposal at submission time
+    #[serde(default)]
+    deposits: HashMap<u64, u64>,
+    /// Token the deposit and votes of each proposal are escrowed in
+    #[serde(default)]
+    escrow_tokens: HashMap<u64, TokenConfig>,
+    /// Refunds and rewards paid out for each proposal
+    #[serde(default)]
+    payouts: HashMap<u64, Vec<PayoutReport>>,
+    /// Votes whose escrow is being transferred, one change per voter and proposal at a time
+    #[serde(skip)]
+    settling_votes: HashSet<(u64, Principal)>,
+    /// Vote weight percent of each voter of the open proposals, taken when they voted
+    #[serde(default)]
+    vote_weights: HashMap<u64, HashMap<Principal, u64>>,
+    /// Proposals whose votes are being tallied and paid out
+    #[serde(skip)]
+    settling_proposals: HashSet<u64>,
+    /// When the last archive pass found nothing more to archive
+    #[serde(default)]
+    archived_at: u64,
 }
 
 impl DaoService {
@@ -124,39 +190,147 @@ impl DaoService {
 
         Ok(true)
     }
-    pub async fn propose(&mut self, arg: ProposalBody) -> Result<Proposal, String> {
+    pub fn is_moderator(&self, principal: Principal) -> bool {
+        ic::with(|data: &Data| {
+            self.moderators.contains(&principal) || data.owners.is_owner(principal).is_ok()
+        })
+    }
+    pub fn add_moderator(&mut self, principal: Principal) -> Vec<Principal> {
+        if !self.moderators.contains(&principal) {
+            self.moderators.push(principal);
+            tools::record_event(ic::caller(), EventKind::AddModerator { principal });
+        }
+        self.moderators.clone()
+    }
+    pub fn remove_moderator(&mut self, principal: Principal) -> Vec<Principal> {
+        if self.moderators.contains(&principal) {
+            self.moderators.retain(|item| *item != principal);
+            tools::record_event(ic::caller(), EventKind::RemoveModerator { principal });
+        }
+        self.moderators.clone()
+    }
+    pub fn get_moderators(&self) -> Vec<Principal> {
+        self.moderators.clone()
+    }
+    /// Check the treasury can pay out the amount
+    fn validate_amount(&self, amount: &Amount, to_subaccount: bool) -> Result<(), String> {
+        if let (Amount::DIP20(..), true) = (amount, to_subaccount) {
+            return Err(String::from("DIP20 transfers do not support subaccounts"));
+        }
+        if let Amount::Token(symbol, _) = amount {
+            let token = self.config.token(symbol)?;
+            if to_subaccount && !token.supports_subaccounts() {
+                return Err(format!("{} transfers do not support subaccounts", symbol));
+            }
+        }
+        Ok(())
+    }
+    fn validate_payload(&self, payload: &ProposalPayload) -> Result<(), String> {
+        match payload {
+            ProposalPayload::Text => Ok(()),
+            ProposalPayload::TreasuryTransfer(transfer) => {
+                if transfer.amount.is_zero() {
+                    return Err(String::from("Transfer amount must not be zero"));
+                }
+                self.validate_amount(&transfer.amount, transfer.to_subaccount.is_some())
+            }
+            ProposalPayload::Bounty(bounty) => {
+                BountyService::validate(bounty)?;
+                self.validate_amount(&bounty.reward, false)
+            }
+            ProposalPayload::CreateStream(stream) => {
+                StreamService::validate(stream)?;
+                self.validate_amount(&stream.total, stream.to_subaccount.is_some())
+            }
+            ProposalPayload::AllocateBudget(budget) => {
+                BudgetService::validate(budget)?;
+                self.validate_amount(&budget.allocation, false)
+            }
+            ProposalPayload::CancelStream(id) => {
+                ic::with(|data: &Data| match data.stream.get_stream(*id)?.state {
+                    StreamState::Active => Ok(()),
+                    _ => Err(String::from("Stream is not active")),
+                })
+            }
+            ProposalPayload::ParameterChange(change) => match change {
+                ParameterChange::ProposalRule(_, rule) => rule.validate(),
+                ParameterChange::RegisterToken(token) => {
+                    if token.symbol.is_empty() {
+                        return Err(String::from("Token symbol must not be empty"));
+                    }
+                    Ok(())
+                }
+                ParameterChange::RemoveToken(symbol) => {
+                    if *symbol == self.config.governance_token {
+                        return Err(String::from("The governance token can not be removed"));
+                    }
+                    self.config.token(symbol).map(|_| ())
+                }
+                ParameterChange::GovernanceToken(symbol) => self.config.token(symbol).map(|_| ()),
+                ParameterChange::EditPolicy(_)
+                | ParameterChange::ProposalDeposit(_)
+                | ParameterChange::AdmissionMode(_)
+                | ParameterChange::FeePolicy(_) => Ok(()),
+            },
+            ProposalPayload::MembershipChange(change) => {
+                let joined = self
+                    .member_list
+                    .get(&change.principal)
+                    .is_some_and(|member| member.status_code == 1);
+                match change.action {
+                    MembershipAction::Add if joined => {
+                        Err(String::from("User is already a member of this DAO"))
+                    }
+                    MembershipAction::Remove if !joined => {
+                        Err(String::from("User is not a member of this DAO"))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            // nothing executes an accepted upgrade yet, so none can be submitted
+            ProposalPayload::CodeUpgrade(_) => {
+                Err(String::from("Code upgrade proposals are not supported yet"))
+            }
+            ProposalPayload::DaoInfoUpdate(info) => {
+                if info.name.is_empty() {
+                    return Err(String::from("DAO name must not be empty"));
+                }
+                Ok(())
+            }
+        }
+    }
+    pub async fn propose(&mut self, mut arg: ProposalBody) -> Result<Proposal, String> {
+        self.validate_payload(&arg.payload)?;
+        let rule = self.config.proposal_rule(arg.payload.kind());
+        if arg.start_time == 0 {
+            arg.start_time = ic::time();
+        }
+        if arg.end_time == 0 {
+            arg.end_time = arg.start_time + rule.voting_period;
+        }
+        if arg.end_time < arg.start_time + rule.voting_period {
+            return Err(format!(
+                "Voting period must be at least {} seconds",
+                rule.voting_period / 1_000_000_000
+            ));
+        }
         // check balances
-        // let caller = ic_cdk::caller();
-        let dip_client =
-            dip20::Service::new(Principal::from_text("vgqnj-miaaa-aaaal-qaapa-cai").unwrap());
-        let balance = dip_client.balanceOf(arg.proposer).await.unwrap();
-        let dao_principal = Principal::from_text("67bzx-5iaaa-aaaam-aah5a-cai").unwrap();
-
-        // 1 ndp
-        let amount: i64 = 1;
-        let amount = candid::Nat((amount).to_biguint().unwrap());
-        if balance.0 < amount {
+        // let caller = ic::caller();
+        let token = self.config.governance_token()?;
+        let ledger = token.ledger();
+        let units = token.units().await?;
+        let balance = ledger.balance_of(arg.proposer, None).await?;
+
+        let deposit = units.whole(self.config.proposal_deposit)?;
+        if balance < deposit + units.fee {
             return Err(String::from("Insufficient balance!"));
         }
-        // // approve
-        // let approved = dip_client.approve(dao_principal, amount.clone()).await;
-        // if let Err(_str) = approved {
-        //     return Err("Approve failed".to_string());
-        // }
-        // ic_cdk::println!("approved {:#?}", approved);
-
-        // let allow = dip_client
-        //     .allowance(arg.proposer, dao_principal)
-        //     .await
-        //     .unwrap();
-
-        // if allow.0 < amount {
-        //     return Err("Approved insufficient NDP count".to_string());
-        // }
+        let deposit = u64::try_from(deposit).map_err(|_| "Deposit is too large".to_string())?;
+        if ledger.allowance(arg.proposer).await? < deposit as u128 {
+            return Err(String::from("Approved amount is insufficient"));
+        }
         // transfer
-        let transfer = dip_client
-            .transferFrom(arg.proposer, dao_principal, amount.clone())
-            .await;
+        let transfer = ledger.transfer_from(arg.proposer, deposit as u128).await;
 
         // ic_cdk::println!("transfer {:#?}", transfer);
         if let Err(_str) = transfer {
@@ -172,8 +346,19 @@ impl DaoService {
                 property: arg.property,
                 start_time: arg.start_time,
                 end_time: arg.end_time,
+                payload: arg.payload,
             })
             .await?;
+        self.deposits.insert(proposal_info.id, deposit);
+        self.escrow_tokens.insert(proposal_info.id, token.clone());
+        tools::record_token_flow(
+            FlowDirection::Inflow,
+            FlowKind::ProposalFee,
+            &token.symbol,
+            deposit,
+            arg.proposer,
+            Some(proposal_info.id),
+        );
         // self.proposer_list.push(ProposerListItem {
         //     proposer: arg.proposer,
         //     id: proposal_info.id,
@@ -181,70 +366,201 @@ impl DaoService {
         self.pending_proposal.push(proposal_info.id);
         Ok(proposal_info)
     }
-    async fn validate_before_vote(&mut self, vote_arg: UserVoteArgs) -> Result<bool, String> {
+    fn validate_before_vote(&self, vote_arg: &UserVoteArgs) -> Result<Proposal, String> {
         self.is_member(vote_arg.principal.unwrap())?;
         // owner can not vote for self;
         let proposal_info = self.basic.get_proposal(vote_arg.id)?;
         // valida start_time & end_time
-        if ic_cdk::api::time() < proposal_info.start_time {
+        if ic::time() < proposal_info.start_time {
             return Err("Voting has not yet started".to_owned());
         }
-        if ic_cdk::api::time() > proposal_info.end_time {
+        if ic::time() > proposal_info.end_time {
             return Err("Voting has ended".to_owned());
         }
-        // // can only vote Open proposal
-        // if proposal_info.proposal_state != ProposalState::Open {
-        //     return Err("Voting has closed".to_string());
-        // }
+        // can only vote Open proposal
+        if proposal_info.proposal_state != ProposalState::Open {
+            return Err("Voting has closed".to_string());
+        }
         if let Some(principal) = vote_arg.principal {
             if principal == proposal_info.proposer {
                 return Err("You can't vote for yourself!".to_string());
             }
         }
-        let caller = ic_cdk::caller();
-        // check balance
-        let dip_client =
-            dip20::Service::new(Principal::from_text("vgqnj-miaaa-aaaal-qaapa-cai").unwrap());
-        let dao_principal = Principal::from_text("67bzx-5iaaa-aaaam-aah5a-cai").unwrap();
-        let balance = dip_client
-            .balanceOf(vote_arg.principal.unwrap())
-            .await
-            .unwrap();
-        // 1 ndp
-        let amount: i64 = 1_0000_0000;
-        let amount = integer_to_nat(amount);
+        Ok(proposal_info)
+    }
+    /// Settle the escrow difference between the previous and the new voting weight
+    async fn settle_vote_escrow(
+        &mut self,
+        id: u64,
+        voter: Principal,
+        previous: Equities,
+        current: Equities,
+    ) -> Result<(), String> {
+        let token = self.escrow_token(id);
+        let ledger = token.ledger();
 
-        let has_enough_balance = match vote_arg.vote {
-            Votes::Yes(count) | Votes::No(count) => {
-                balance.0 >= candid::Nat((count).to_biguint().unwrap())
+        if current > previous {
+            // check balance
+            let units = token.units().await?;
+            let balance = ledger.balance_of(voter, None).await?;
+            // 1 whole token
+            let amount = units.whole(1)?;
+            let delta = current - previous;
+            if balance < amount || balance < delta as u128 + units.fee {
+                return Err(String::from("Insufficient balance"));
             }
-        };
-        if balance.0 < amount || !has_enough_balance {
-            return Err(String::from("Insufficient balance"));
+            if ledger.allowance(voter).await? < delta as u128 {
+                return Err(String::from("Approved amount is insufficient"));
+            }
+            // transfer
+            let transfer = ledger.transfer_from(voter, delta as u128).await;
+            if let Err(_str) = transfer {
+                return Err("Transfer failed!".to_string());
+            }
+            tools::record_token_flow(
+                FlowDirection::Inflow,
+                FlowKind::VoteEscrow,
+                &token.symbol,
+                delta,
+                voter,
+                Some(id),
+            );
+        } else if current < previous {
+            // give back the escrowed difference
+            let units = token.units().await;
+            let parts = [(FlowKind::Refund, previous - current)];
+            if !self.payout(id, &token, &units, voter, &parts).await {
+                return Err("Refund failed!".to_string());
+            }
+        }
+        Ok(())
+    }
+    /// The token a proposal's deposit and votes are escrowed in
+    fn escrow_token(&self, id: u64) -> TokenConfig {
+        self.escrow_tokens
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(DaoConfig::legacy_escrow_token)
+    }
+    /// Pay escrowed tokens out in a single transfer, the ledger fee is deducted from the
+    /// payout or absorbed by the treasury depending on the fee policy. Every payout is
+    /// reported with the net amount the recipient received. `units` are fetched once by
+    /// the caller for all payouts of a proposal.
+    async fn payout(
+        &mut self,
+        id: u64,
+        token: &TokenConfig,
+        units: &Result<TokenUnits, String>,
+        to: Principal,
+        parts: &[(FlowKind, u64)],
+    ) -> bool {
+        let count: u64 = parts.iter().map(|(_, amount)| amount).sum();
+        if count == 0 {
+            return true;
         }
-        // caculate weight
-        let amount = match vote_arg.vote {
-            Votes::Yes(num) => integer_to_nat(num as i64),
-            Votes::No(num) => integer_to_nat(num as i64),
+        let mut report = PayoutReport {
+            recipient: to,
+            token: token.symbol.clone(),
+            gross: count,
+            fee: 0,
+            net: 0,
+            block_index: None,
+            error: None,
+            timestamp: ic::time(),
         };
-
-        // allow.0 may block_height ,not approved amount
-        // let allow = dip_client.allowance(caller, dao_principal).await.unwrap();
-
-        // if allow.0 < amount {
-        //     return Err("Approved insufficient NDP count".to_string());
-        // }
-        // transfer
-        let transfer = dip_client
-            .transferFrom(caller, dao_principal, amount.clone())
-            .await;
-
-        // ic_cdk::println!("transfer {:#?}", transfer);
-        if let Err(_str) = transfer {
-            return Err("Transfer failed!".to_string());
+        let result = match units {
+            Ok(units) => {
+                report.fee = units.fee;
+                report.net = match self.config.fee_policy {
+                    FeePolicy::Deduct => units.after_fee(count as u128),
+                    FeePolicy::Absorb => count as u128,
+                };
+                if report.net == 0 {
+                    // nothing left to send, the treasury keeps the dust
+                    report.error = Some(String::from("payout does not cover the ledger fee"));
+                    self.payouts.entry(id).or_default().push(report);
+                    return true;
+                }
+                token
+                    .ledger()
+                    .transfer(None, to, None, report.net, id)
+                    .await
+            }
+            Err(err) => Err(err.clone()),
+        };
+        let paid = match result {
+            Ok(block_index) => {
+                report.block_index = Some(block_index);
+                for (kind, amount) in parts {
+                    tools::record_token_flow(
+                        FlowDirection::Outflow,
+                        *kind,
+                        &token.symbol,
+                        *amount,
+                        to,
+                        Some(id),
+                    );
+                }
+                if self.config.fee_policy == FeePolicy::Absorb && report.fee > 0 {
+                    tools::record_token_flow(
+                        FlowDirection::Outflow,
+                        FlowKind::Fee,
+                        &token.symbol,
+                        report.fee as u64,
+                        to,
+                        Some(id),
+                    );
+                }
+                true
+            }
+            Err(err) => {
+                let result = (
+                    id,
+                    Err(format!("{} failed transfer {}: {}", to, count, err)),
+                );
+                self.proposal_log.push(result);
+                report.net = 0;
+                report.error = Some(err);
+                false
+            }
+        };
+        self.payouts.entry(id).or_default().push(report);
+        paid
+    }
+    pub fn get_proposal_payouts(&self, id: u64) -> Vec<PayoutReport> {
+        self.payouts.get(&id).cloned().unwrap_or_default()
+    }
+    /// Archive the finalized proposals older than the configured age
+    pub fn archive_proposals(&mut self, now: u64) -> Vec<u64> {
+        let age = self.config.archive_after_seconds;
+        if age == 0 {
+            return vec![];
         }
-
-        Ok(true)
+        if now.saturating_sub(self.archived_at) < ARCHIVE_INTERVAL_SECONDS * 1_000_000_000 {
+            return vec![];
+        }
+        let before = now.saturating_sub(age.saturating_mul(1_000_000_000));
+        let ids = self.basic.archive_proposals(before, MAX_ARCHIVED_PER_BEAT);
+        // a full batch may have left more behind, the next beat carries on
+        if ids.len() < MAX_ARCHIVED_PER_BEAT {
+            self.archived_at = now;
+        }
+        ids
+    }
+    /// Change the state of a proposal and record it in the event log
+    fn set_proposal_state(&mut self, id: u64, state: ProposalState) -> Result<(), String> {
+        self.basic.change_proposal_state(ChangeProposalStateArg {
+            id,
+            state: state.clone(),
+        })?;
+        tools::record_event(
+            ic::id(),
+            EventKind::ProposalState {
+                proposal_id: id,
+                state,
+            },
+        );
+        Ok(())
     }
     pub fn proposal_list(&self) -> std::collections::hash_map::IntoIter<u64, Proposal> {
         self.basic.proposal_list().into_iter()
@@ -259,44 +575,66 @@ impl DaoService {
         }
         let id = id.unwrap();
 
-        let now = ic_cdk::api::time();
-        let proposal = self.basic.proposal_list.get_mut(&id);
-        if proposal.is_none() {
-            return;
-        }
-        let proposal = proposal.unwrap();
-        if now <= proposal.end_time {
-            //  reinqueue
-            self.pending_proposal.push(id);
+        let now = ic::time();
+        let proposal = match self.basic.proposal_list.get(&id) {
+            Some(proposal) => proposal,
+            None => return,
+        };
+        if now <= proposal.end_time || self.is_settling(id) {
+            //  reinqueue behind the other pending proposals
+            self.pending_proposal.insert(0, id);
             return;
         }
 
-        let dip_client =
-            dip20::Service::new(Principal::from_text("vgqnj-miaaa-aaaal-qaapa-cai").unwrap());
+        // cancelling waits until the payouts below are done, see `cancel_proposal`
+        self.settling_proposals.insert(id);
+        self.settle_proposal(id, proposal).await;
+        self.settling_proposals.remove(&id);
+    }
+    /// Tally the votes of a proposal whose voting has ended, pay out and execute it
+    async fn settle_proposal(&mut self, id: u64, proposal: Proposal) {
+        let token = self.escrow_token(id);
 
         if proposal.proposal_state == ProposalState::Open {
+            let units = token.units().await;
+            // the quorum is kept in whole tokens, try again once the ledger answers
+            let rule = match &units {
+                Ok(units) => self
+                    .config
+                    .proposal_rule(proposal.payload.kind())
+                    .in_units(units),
+                Err(err) => {
+                    self.proposal_log.push((id, Err(err.clone())));
+                    self.pending_proposal.insert(0, id);
+                    return;
+                }
+            };
             // caculate weight
             let mut yes = 0;
-            let mut yes_count = 0;
             let mut no = 0;
             let mut no_count = 0;
+            // weights scaled by reputation decide the outcome, payouts use the tokens
+            let mut weighted_yes: Equities = 0;
+            let mut weighted_no: Equities = 0;
+            // votes cast before weights were stored count their tokens
+            let percents = self.vote_weights.remove(&id).unwrap_or_default();
             for vote in &proposal.vote_data {
+                let percent = percents.get(&vote.0).copied().unwrap_or(100);
+                let weighted = reputation::weighted(vote.1.weight(), percent);
                 match vote.1 {
                     Votes::Yes(count) => {
                         yes += count;
-                        yes_count += 1
+                        weighted_yes = weighted_yes.saturating_add(weighted);
                     }
                     Votes::No(count) => {
                         no += count;
                         no_count += 1;
+                        weighted_no = weighted_no.saturating_add(weighted);
                     }
                 }
             }
             if yes == 0 && no == 0 {
-                if let Err(err) = self.basic.change_proposal_state(ChangeProposalStateArg {
-                    id,
-                    state: ProposalState::Rejected,
-                }) {
+                if let Err(err) = self.set_proposal_state(id, ProposalState::Rejected) {
                     let result = (id, Err(err));
                     self.proposal_log.push(result);
                     return;
@@ -305,107 +643,411 @@ impl DaoService {
                 self.proposal_log.push(result);
                 return;
             }
+            let accepted = rule.is_accepted(weighted_yes, weighted_no);
+            let quorum_reached = rule.reaches_quorum(weighted_yes, weighted_no);
             // reward yes
-            if yes > no {
+            if accepted {
                 // return proposer ndp;
-                let proposal_amount = 1;
+                let proposal_amount = self.deposits.get(&id).copied().unwrap_or(1);
                 // Divide equally left ndp
                 let per_count = no / (no_count + 1);
-                if (dip_client
-                    .transfer_token(
-                        proposal.proposer,
-                        candid::Nat((proposal_amount + per_count).to_biguint().unwrap()),
-                    )
-                    .await)
-                    .is_err()
-                {
-                    let result = (
-                        id,
-                        Err(format!(
-                            "{} failed transfer {}",
-                            proposal.proposer.clone().to_text(),
-                            proposal_amount + per_count
-                        )),
-                    );
-                    self.proposal_log.push(result);
-                    //    continue fallback vote user ndp
-                }
+                //    continue fallback vote user ndp on failure
+                self.payout(
+                    id,
+                    &token,
+                    &units,
+                    proposal.proposer,
+                    &[
+                        (FlowKind::Refund, proposal_amount),
+                        (FlowKind::Reward, per_count),
+                    ],
+                )
+                .await;
 
                 for vote in &proposal.vote_data {
                     match vote.1 {
                         Votes::Yes(count) => {
-                            if (dip_client
-                                .transfer_token(
-                                    vote.0,
-                                    candid::Nat((per_count + count).to_biguint().unwrap()),
-                                )
-                                .await)
-                                .is_err()
-                            {
-                                let result =
-                                    (id, Err(format!("{} failed transfer {}", vote.0, count)));
-                                self.proposal_log.push(result);
-                                continue;
-                            }
+                            self.payout(
+                                id,
+                                &token,
+                                &units,
+                                vote.0,
+                                &[(FlowKind::Refund, count), (FlowKind::Reward, per_count)],
+                            )
+                            .await;
                         }
                         Votes::No(_count) => (),
                     }
                 }
             } else {
-                // give back no
+                // give back no, and yes as well when the quorum was not reached
                 // let per_count = yes / yes_count;
                 for vote in &proposal.vote_data {
-                    match vote.1 {
-                        Votes::Yes(_count) => {}
-                        Votes::No(count) => {
-                            if (dip_client
-                                .transfer_token(vote.0, candid::Nat((&count).to_biguint().unwrap()))
-                                .await)
-                                .is_err()
-                            {
-                                let result =
-                                    (id, Err(format!("{} failed transfer {}", vote.0, count)));
-                                self.proposal_log.push(result);
-                                continue;
-                            }
-                        }
-                    }
+                    let count = match vote.1 {
+                        Votes::Yes(count) if !quorum_reached => count,
+                        Votes::Yes(_count) => continue,
+                        Votes::No(count) => count,
+                    };
+                    self.payout(id, &token, &units, vote.0, &[(FlowKind::Refund, count)])
+                        .await;
                 }
             }
-            if let Err(err) = self.basic.change_proposal_state(ChangeProposalStateArg {
+            if let Err(err) = self.set_proposal_state(
                 id,
-                state: if yes > no {
+                if accepted {
                     ProposalState::Accepted
                 } else {
                     ProposalState::Rejected
                 },
-            }) {
+            ) {
                 let result = (id, Err(err));
                 self.proposal_log.push(result);
                 return;
             }
             let result = (id, Ok(format!("completed yes:{} no:{}", yes, no)));
             self.proposal_log.push(result);
+            if accepted {
+                self.execute_proposal(id);
+            }
         }
     }
+    /// Apply the payload of an accepted proposal, text proposals need no execution
+    fn execute_proposal(&mut self, id: u64) {
+        let proposal = match self.basic.get_proposal(id) {
+            Ok(proposal) => proposal,
+            Err(_) => return,
+        };
+        if let ProposalPayload::Text = proposal.payload {
+            return;
+        }
+        if let Err(err) = self.set_proposal_state(id, ProposalState::Executing) {
+            self.proposal_log.push((id, Err(err)));
+            return;
+        }
+        let result = match proposal.payload {
+            ProposalPayload::TreasuryTransfer(transfer) => {
+                // the proposal stays executing until the disbursement is settled
+                ic::with_mut(|data: &mut Data| {
+                    let disbursement = Disbursement {
+                        canister: ic::id().to_text(),
+                        token_idf: id.to_string(),
+                        from_subaccount: None,
+                        to: transfer.to,
+                        to_subaccount: transfer.to_subaccount,
+                        amount: transfer.amount,
+                        try_num: 0,
+                        proposal_id: Some(id),
+                        bounty_id: None,
+                        stream_id: None,
+                        stream_payment: None,
+                        budget_spend: None,
+                        token: None,
+                    };
+                    data.disburse.add_disbursement(disbursement, &self.config)
+                });
+                self.proposal_log
+                    .push((id, Ok(String::from("disbursement queued"))));
+                return;
+            }
+            ProposalPayload::ParameterChange(change) => {
+                self.apply_parameter_change(change);
+                Ok(())
+            }
+            ProposalPayload::DaoInfoUpdate(info) => self.update_dao_info(info).map(|_| ()),
+            ProposalPayload::Bounty(arg) => {
+                let proposer = proposal.proposer;
+                ic::with_mut(|data: &mut Data| data.bounty.create(proposer, arg, Some(id)))
+                    .map(|_| ())
+            }
+            ProposalPayload::CreateStream(arg) => ic::with_mut(|data: &mut Data| {
+                data.stream.create(arg, Some(id));
+                Ok(())
+            }),
+            ProposalPayload::AllocateBudget(arg) => ic::with_mut(|data: &mut Data| {
+                data.budget.create(arg, Some(id));
+                Ok(())
+            }),
+            ProposalPayload::CancelStream(stream_id) => {
+                ic::with_mut(|data: &mut Data| data.stream.cancel(stream_id).map(|_| ()))
+            }
+            ProposalPayload::MembershipChange(change) => self.apply_membership_change(change),
+            _ => Err(String::from("Proposal kind can not be executed yet")),
+        };
+        let state = match &result {
+            Ok(_) => ProposalState::Succeeded,
+            Err(err) => ProposalState::Failed(err.clone()),
+        };
+        if let Err(err) = self.set_proposal_state(id, state) {
+            self.proposal_log.push((id, Err(err)));
+            return;
+        }
+        self.proposal_log
+            .push((id, result.map(|_| String::from("executed"))));
+    }
+    /// Record the outcome of the disbursement queued by a treasury transfer proposal
+    pub fn settle_treasury_transfer(
+        &mut self,
+        disbursement: &Disbursement,
+        result: &Result<BlockIndex, String>,
+    ) {
+        let id = match disbursement.proposal_id {
+            Some(id) => id,
+            None => return,
+        };
+        let state = match result {
+            Ok(block) => {
+                let _ = self.basic.with_proposal(id, |proposal| {
+                    proposal.block_index = Some(*block);
+                    Ok(())
+                });
+                ProposalState::Succeeded
+            }
+            Err(err) if disbursement.try_num >= MAX_DISBURSEMENT_TRIES => {
+                ProposalState::Failed(err.clone())
+            }
+            Err(err) => {
+                self.proposal_log.push((id, Err(err.clone())));
+                return;
+            }
+        };
+        if let Err(err) = self.set_proposal_state(id, state) {
+            self.proposal_log.push((id, Err(err)));
+            return;
+        }
+        let result = result
+            .clone()
+            .map(|block| format!("disbursed at block {}", block));
+        self.proposal_log.push((id, result));
+    }
+    fn apply_parameter_change(&mut self, change: ParameterChange) {
+        match change {
+            ParameterChange::EditPolicy(policy) => self.config.edit_policy = policy,
+            ParameterChange::ProposalRule(kind, rule) => {
+                self.config.proposal_rules.insert(kind, rule);
+            }
+            ParameterChange::ProposalDeposit(deposit) => self.config.proposal_deposit = deposit,
+            ParameterChange::AdmissionMode(mode) => self.config.admission_mode = mode,
+            ParameterChange::FeePolicy(policy) => self.config.fee_policy = policy,
+            ParameterChange::RegisterToken(token) => {
+                self.config
+                    .tokens
+                    .retain(|item| item.symbol != token.symbol);
+                self.config.tokens.push(token);
+            }
+            ParameterChange::RemoveToken(symbol) => {
+                self.config.tokens.retain(|item| item.symbol != symbol)
+            }
+            ParameterChange::GovernanceToken(symbol) => self.config.governance_token = symbol,
+        }
+    }
+    fn apply_membership_change(&mut self, change: MembershipChange) -> Result<(), String> {
+        match change.action {
+            MembershipAction::Add => {
+                let now = ic::time();
+                let existing = self.member_list.get(&change.principal);
+                if existing.as_ref().is_some_and(MemberItems::is_joined) {
+                    return Ok(());
+                }
+                let mut member = existing.unwrap_or_else(|| MemberItems {
+                    principal: change.principal,
+                    nickname: Default::default(),
+                    status_code: 1,
+                    avatar: Default::default(),
+                    intro: Default::default(),
+                    social: Default::default(),
+                    join_at: now,
+                    last_visit_at: now,
+                });
+                member.status_code = 1;
+                self.store_member(member);
+                tools::record_event(change.principal, EventKind::Join);
+                Ok(())
+            }
+            MembershipAction::Remove => self.quit(change.principal).map(|_| ()),
+        }
+    }
+    /// Give back escrowed tokens, failures are kept in the proposal log
+    async fn refund(&mut self, id: u64, refunds: &[(Principal, u64)]) {
+        if refunds.is_empty() {
+            return;
+        }
+        let token = self.escrow_token(id);
+        let units = token.units().await;
+        for (to, count) in refunds {
+            self.payout(id, &token, &units, *to, &[(FlowKind::Refund, *count)])
+                .await;
+        }
+    }
+    /// Only the proposer may edit, once voting has started the DAO's `EditPolicy` applies
+    pub async fn edit_proposal(
+        &mut self,
+        caller: Principal,
+        arg: EditProposalArg,
+    ) -> Result<Proposal, String> {
+        let proposal = self.basic.get_proposal(arg.id)?;
+        if proposal.proposal_state != ProposalState::Open {
+            return Err(String::from("Only open proposals can be edited"));
+        }
+        if caller != proposal.proposer {
+            return Err(String::from("no auth"));
+        }
+        let now = ic::time();
+        if now > proposal.end_time {
+            return Err("Voting has ended".to_owned());
+        }
+        let mut votes = Vec::new();
+        if now >= proposal.start_time {
+            match self.config.edit_policy {
+                EditPolicy::Locked => {
+                    return Err(String::from(
+                        "Proposal can not be edited once voting has started",
+                    ))
+                }
+                EditPolicy::ResetVotes => {
+                    if self.is_settling(arg.id) {
+                        return Err(String::from(
+                            "Votes on this proposal are still being settled",
+                        ));
+                    }
+                    votes = self.basic.reset_votes(arg.id)?;
+                    self.vote_weights.remove(&arg.id);
+                }
+            }
+        }
+        let id = arg.id;
+        let proposal = self.basic.edit_proposal(arg)?;
+        let refunds: Vec<(Principal, u64)> = votes
+            .iter()
+            .map(|(voter, vote)| (*voter, vote.weight()))
+            .collect();
+        self.refund(id, &refunds).await;
+        Ok(proposal)
+    }
+    /// The proposer may cancel before any votes or before `start_time`, moderators at any time
+    pub async fn cancel_proposal(
+        &mut self,
+        caller: Principal,
+        id: u64,
+    ) -> Result<Proposal, String> {
+        let proposal = self.basic.get_proposal(id)?;
+        let is_proposer = caller == proposal.proposer
+            && (proposal.vote_data.is_empty() || ic::time() < proposal.start_time);
+        if !is_proposer && !self.is_moderator(caller) {
+            return Err(String::from("no auth"));
+        }
+        if self.is_settling(id) {
+            return Err(String::from(
+                "Votes on this proposal are still being settled",
+            ));
+        }
+        if self.settling_proposals.contains(&id) {
+            return Err(String::from("The proposal is being settled"));
+        }
+        let (proposal, votes) = self.basic.cancel_proposal(id)?;
+        self.vote_weights.remove(&id);
+        tools::record_event(
+            caller,
+            EventKind::ProposalState {
+                proposal_id: id,
+                state: ProposalState::Cancelled,
+            },
+        );
+        self.pending_proposal.retain(|item| *item != id);
+
+        // return proposer ndp and the escrowed votes
+        let proposal_amount = self.deposits.get(&id).copied().unwrap_or(1);
+        let refunds: Vec<(Principal, u64)> = std::iter::once((proposal.proposer, proposal_amount))
+            .chain(votes.iter().map(|(voter, vote)| (*voter, vote.weight())))
+            .collect();
+        self.refund(id, &refunds).await;
+        let result = (id, Ok(format!("cancelled by {}", caller.to_text())));
+        self.proposal_log.push(result);
+        Ok(proposal)
+    }
+    /// The vote is stored before the escrow is settled so that concurrent calls see it,
+    /// it is put back when the transfer or refund fails
     pub async fn vote(&mut self, mut arg: UserVoteArgs) -> Result<(), String> {
-        let caller = ic_cdk::caller();
+        let caller = ic::caller();
         arg.principal = Some(caller);
-        let valid = self.validate_before_vote(arg.clone()).await?;
-        if !valid {
-            return Err(String::from("vote failed"));
-        }
-        self.basic
-            .vote(VotesArg {
-                id: arg.id,
-                caller,
-                vote: arg.vote,
-            })
-            .await?;
-        // self.votes_list.push(arg);
-        Ok(())
+        self.validate_before_vote(&arg)?;
+        let current = arg.vote.weight();
+        self.settle_vote_change(arg.id, caller, Some(arg.vote), current)
+            .await
+    }
+    pub async fn withdraw_vote(&mut self, id: u64) -> Result<(), String> {
+        let caller = ic::caller();
+        self.validate_before_vote(&UserVoteArgs {
+            principal: Some(caller),
+            id,
+            vote: Votes::Yes(0),
+        })?;
+        self.settle_vote_change(id, caller, None, 0).await
+    }
+    /// Whether a vote change on the proposal is waiting for the ledger
+    fn is_settling(&self, id: u64) -> bool {
+        self.settling_votes
+            .iter()
+            .any(|(vote_id, _)| *vote_id == id)
+    }
+    /// Replace the vote of `voter`, then settle the escrow of the change
+    async fn settle_vote_change(
+        &mut self,
+        id: u64,
+        voter: Principal,
+        vote: Option<Votes>,
+        current: Equities,
+    ) -> Result<(), String> {
+        if !self.settling_votes.insert((id, voter)) {
+            return Err(String::from(
+                "A previous vote change is still being settled",
+            ));
+        }
+        let voted = vote.is_some();
+        let previous = match self.basic.set_vote(id, voter, vote) {
+            Ok(previous) => previous,
+            Err(err) => {
+                self.settling_votes.remove(&(id, voter));
+                return Err(err);
+            }
+        };
+        let weight = previous.as_ref().map(Votes::weight).unwrap_or_default();
+        let result = self.settle_vote_escrow(id, voter, weight, current).await;
+        if result.is_err() {
+            // the proposal stays open while the change is in flight, see `check_proposal`
+            if let Err(err) = self.basic.set_vote(id, voter, previous) {
+                tools::log_message(
+                    LogLevel::Error,
+                    voter,
+                    "restore_vote",
+                    format!("proposal {}: {}", id, err),
+                    vec![],
+                );
+            }
+        }
+        if result.is_ok() {
+            let weights = self.vote_weights.entry(id).or_default();
+            if voted {
+                weights.insert(voter, tools::vote_weight_percent(voter, &self.config));
+            } else {
+                weights.remove(&voter);
+            }
+        }
+        self.settling_votes.remove(&(id, voter));
+        result
     }
 
+    pub fn dao_config(&self) -> DaoConfig {
+        self.config.clone()
+    }
+    pub fn update_dao_config(&mut self, config: DaoConfig) -> Result<DaoConfig, String> {
+        config.validate()?;
+        self.config = config;
+        Ok(self.dao_config())
+    }
+    /// Replace the config without checking it, for migrations of stored configs
+    pub(crate) fn set_dao_config(&mut self, config: DaoConfig) {
+        self.config = config;
+    }
     pub fn dao_info(&self) -> Result<DaoInfo, String> {
         Ok(self.info.clone())
     }
@@ -413,10 +1055,25 @@ impl DaoService {
         let created_at = self.info.created_at;
         self.info = dao_info;
         self.info.created_at = created_at;
+        tools::certify(|certified| certified.set_dao_info(&self.info));
         self.dao_info()
     }
+    /// Update the certified data and the statistics once a member was written
+    fn member_changed(member: &MemberItems) {
+        tools::certify(|certified| certified.set_member(member.principal, member));
+        ic::with_mut(|data: &mut Data| data.analytics.member_changed(member, ic::time()));
+    }
+    /// Save a member and update the certified data
+    fn store_member(&mut self, member: MemberItems) {
+        self.member_list.insert(member.principal, member.clone());
+        Self::member_changed(&member);
+    }
     pub fn member_list(&self) -> Result<Vec<MemberItems>, String> {
-        Ok(self.member_list.values().cloned().collect())
+        Ok(self.member_list.values().collect())
+    }
+    /// The total number of members and one page of them
+    pub fn member_page(&self, offset: usize, limit: usize) -> (u64, Vec<MemberItems>) {
+        (self.member_list.len(), self.member_list.page(offset, limit))
     }
     pub fn join(
         &mut self,
@@ -429,6 +1086,25 @@ impl DaoService {
         //     return Ok(item.clone());
         // }
 
+        // members admitted by proposal keep their join time when filling in their profile
+        let admitted = self
+            .member_list
+            .get(&principal)
+            .filter(|member| member.status_code == 1)
+            .map(|member| member.join_at);
+        match self.config.admission_mode {
+            AdmissionMode::Open => (),
+            AdmissionMode::Proposal if admitted.is_some() => (),
+            AdmissionMode::Proposal => {
+                return Err(String::from(
+                    "Joining this DAO requires an accepted membership proposal",
+                ))
+            }
+            AdmissionMode::Closed => {
+                return Err(String::from("This DAO is not accepting new members"))
+            }
+        }
+
         let member = MemberItems {
             principal,
             nickname: user_info.nickname,
@@ -436,42 +1112,44 @@ impl DaoService {
             avatar: user_info.avatar,
             intro: user_info.intro,
             social: user_info.social,
-            join_at: ic_cdk::api::time(),
-            last_visit_at: ic_cdk::api::time(),
+            join_at: admitted.unwrap_or_else(ic::time),
+            last_visit_at: ic::time(),
         };
-        self.member_list.insert(principal, member.clone());
+        self.store_member(member.clone());
+        tools::record_event(principal, EventKind::Join);
         Ok(member)
     }
     pub fn user_info(&mut self) -> Result<MemberItems, String> {
         // if current user joined this dao ,update last_visit_at timestamp
-        let caller = ic_cdk::caller();
+        let caller = ic::caller();
 
-        if let Some(info) = self.member_list.get_mut(&caller) {
-            info.last_visit_at = ic_cdk::api::time()
-        }
-        self.member_list
-            .get(&caller)
-            .cloned()
-            .ok_or_else(|| "You are not yet a member of this group!".to_string())
+        let member = self
+            .member_list
+            .update(caller, |info| {
+                info.last_visit_at = ic::time();
+                Ok(info.clone())
+            })?
+            .ok_or_else(|| "You are not yet a member of this group!".to_string())?;
+        Self::member_changed(&member);
+        Ok(member)
     }
     pub fn quit(&mut self, principal: Principal) -> Result<MemberItems, String> {
-        let mut member = self
+        let member = self
             .member_list
-            .get_mut(&principal)
+            .update(principal, |member| {
+                member.status_code = -1;
+                Ok(member.clone())
+            })?
             .ok_or_else(|| String::from("You are not yet a member of this group!"))?;
-
-        member.status_code = -1;
-        Ok(member.clone())
+        Self::member_changed(&member);
+        tools::record_event(principal, EventKind::Quit);
+        Ok(member)
     }
     pub fn get_handled_proposal(&self) -> Vec<(u64, Result<String, String>)> {
         self.proposal_log.clone()
     }
 }
 
-fn integer_to_nat(amount: i64) -> candid::Nat {
-    candid::Nat((amount).to_biguint().unwrap())
-}
-
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ProposalContent {
     pub title: String,
@@ -479,6 +1157,7 @@ pub struct ProposalContent {
     pub start_time: u64,
     pub end_time: u64,
     pub property: Option<HashMap<String, String>>,
+    pub payload: Option<ProposalPayload>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -489,6 +1168,7 @@ pub struct ProposalBody {
     pub start_time: u64,
     pub end_time: u64,
     pub property: Option<HashMap<String, String>>,
+    pub payload: ProposalPayload,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -496,3 +1176,244 @@ pub struct ProposerListItem {
     proposer: Principal,
     id: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canister::token_ledger::test_ledger;
+    use crate::test_utils::{block_on, poll, principal, setup};
+    use ic_kit::MockContext;
+    use std::task::Poll;
+
+    /// A proposal with a member voting `Yes(10)` on it, who has 100 tokens left
+    fn voted_proposal() -> (u64, Principal, &'static mut MockContext) {
+        let proposer = principal(1);
+        let voter = principal(2);
+        let context = setup(proposer);
+        let id = ic::with_mut(|data: &mut Data| {
+            for member in [proposer, voter] {
+                data.dao.join(member, JoinDaoParams::default()).unwrap();
+            }
+            let proposal = block_on(data.dao.basic.proposal(ProposalArg {
+                proposer,
+                title: String::from("title"),
+                content: String::from("content"),
+                property: None,
+                start_time: 0,
+                end_time: u64::MAX,
+                payload: ProposalPayload::Text,
+            }))
+            .unwrap();
+            data.dao
+                .basic
+                .set_vote(proposal.id, voter, Some(Votes::Yes(10)))
+                .unwrap();
+            proposal.id
+        });
+        test_ledger::set_balance(ic::id(), 10);
+        test_ledger::set_balance(voter, 100);
+        context.update_caller(voter);
+        (id, voter, context)
+    }
+
+    fn vote_of(id: u64, voter: Principal) -> Option<Votes> {
+        ic::with(|data: &Data| {
+            let proposal = data.dao.basic.get_proposal(id).unwrap();
+            proposal
+                .vote_data
+                .into_iter()
+                .find(|vote| vote.0 == voter)
+                .map(|vote| vote.1)
+        })
+    }
+
+    #[test]
+    fn concurrent_withdrawals_refund_once() {
+        let (id, voter, _) = voted_proposal();
+        test_ledger::pause(true);
+        let mut first = Box::pin(crate::withdraw_vote(id));
+        assert!(poll(first.as_mut()).is_pending());
+        let mut second = Box::pin(crate::withdraw_vote(id));
+        assert!(matches!(poll(second.as_mut()), Poll::Ready(Err(_))));
+        test_ledger::pause(false);
+        assert_eq!(poll(first.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(test_ledger::transfers(), vec![(ic::id(), voter, 10)]);
+        assert_eq!(vote_of(id, voter), None);
+    }
+
+    #[test]
+    fn concurrent_vote_increases_escrow_once() {
+        let (id, voter, _) = voted_proposal();
+        test_ledger::pause(true);
+        let mut first = Box::pin(crate::vote(UserVoteArgs {
+            principal: None,
+            id,
+            vote: Votes::Yes(20),
+        }));
+        assert!(poll(first.as_mut()).is_pending());
+        let mut second = Box::pin(crate::vote(UserVoteArgs {
+            principal: None,
+            id,
+            vote: Votes::Yes(30),
+        }));
+        assert!(matches!(poll(second.as_mut()), Poll::Ready(Err(_))));
+        test_ledger::pause(false);
+        assert_eq!(poll(first.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(test_ledger::transfers(), vec![(voter, ic::id(), 10)]);
+        assert_eq!(vote_of(id, voter), Some(Votes::Yes(20)));
+    }
+
+    #[test]
+    fn failed_refund_restores_the_vote() {
+        let (id, voter, _) = voted_proposal();
+        test_ledger::fail(true);
+        assert!(block_on(crate::withdraw_vote(id)).is_err());
+        assert_eq!(vote_of(id, voter), Some(Votes::Yes(10)));
+        test_ledger::fail(false);
+        assert_eq!(block_on(crate::withdraw_vote(id)), Ok(()));
+        assert_eq!(vote_of(id, voter), None);
+    }
+
+    #[test]
+    fn cancel_clears_and_refunds_the_votes() {
+        let (id, voter, context) = voted_proposal();
+        let moderator = principal(3);
+        ic::with_mut(|data: &mut Data| data.dao.add_moderator(moderator));
+        test_ledger::set_balance(ic::id(), 11);
+        context.update_caller(moderator);
+        let proposal = block_on(crate::cancel_proposal(id)).unwrap();
+        assert_eq!(proposal.proposal_state, ProposalState::Cancelled);
+        assert!(proposal.vote_data.is_empty());
+        assert_eq!(vote_of(id, voter), None);
+        assert!(test_ledger::transfers().contains(&(ic::id(), voter, 10)));
+    }
+
+    #[test]
+    fn proposals_being_settled_are_not_cancelled() {
+        let (id, voter, context) = voted_proposal();
+        let moderator = principal(3);
+        ic::with_mut(|data: &mut Data| {
+            data.dao.add_moderator(moderator);
+            data.dao
+                .basic
+                .with_proposal(id, |proposal| {
+                    proposal.end_time = 1;
+                    Ok(())
+                })
+                .unwrap();
+            data.dao.pending_proposal.push(id);
+        });
+        test_ledger::set_balance(ic::id(), 100);
+        test_ledger::pause(true);
+        let mut settlement = Box::pin(tools::state().dao.check_proposal());
+        assert!(poll(settlement.as_mut()).is_pending());
+        assert!(ic::with(|data: &Data| data
+            .dao
+            .settling_proposals
+            .contains(&id)));
+
+        context.update_caller(moderator);
+        assert!(matches!(
+            poll(Box::pin(crate::cancel_proposal(id)).as_mut()),
+            Poll::Ready(Err(_))
+        ));
+        // the settlement lock holds even if the voting period check would not
+        let cancel =
+            ic::with_mut(|data: &mut Data| block_on(data.dao.cancel_proposal(moderator, id)));
+        assert_eq!(cancel.unwrap_err(), "The proposal is being settled");
+
+        test_ledger::pause(false);
+        assert!(poll(settlement.as_mut()).is_ready());
+        let proposal = ic::with(|data: &Data| data.dao.basic.get_proposal(id)).unwrap();
+        assert_eq!(proposal.proposal_state, ProposalState::Accepted);
+        let refunds = test_ledger::transfers()
+            .into_iter()
+            .filter(|(_, to, _)| *to == voter)
+            .count();
+        assert_eq!(refunds, 1);
+        assert!(!ic::with(|data: &Data| data
+            .dao
+            .settling_proposals
+            .contains(&id)));
+    }
+
+    #[test]
+    fn closed_proposals_are_not_edited_or_reset() {
+        let (id, voter, context) = voted_proposal();
+        ic::with_mut(|data: &mut Data| {
+            data.dao
+                .basic
+                .with_proposal(id, |proposal| {
+                    proposal.proposal_state = ProposalState::Rejected;
+                    Ok(())
+                })
+                .unwrap();
+            assert!(data.dao.basic.reset_votes(id).is_err());
+        });
+        context.update_caller(principal(1));
+        let edit = EditProposalArg {
+            id,
+            title: String::from("edited"),
+            content: String::from("edited"),
+        };
+        assert!(block_on(crate::edit_proposal(edit)).is_err());
+        assert_eq!(vote_of(id, voter), Some(Votes::Yes(10)));
+        assert!(test_ledger::transfers().is_empty());
+    }
+
+    /// Run a membership proposal of `member` through voting and execution
+    fn execute_membership_change(member: Principal, action: MembershipAction) -> ProposalState {
+        let proposer = principal(1);
+        ic::with_mut(|data: &mut Data| {
+            let proposal = block_on(data.dao.basic.proposal(ProposalArg {
+                proposer,
+                title: String::from("title"),
+                content: String::from("content"),
+                property: None,
+                start_time: 0,
+                end_time: 1,
+                payload: ProposalPayload::MembershipChange(MembershipChange {
+                    principal: member,
+                    action,
+                }),
+            }))
+            .unwrap();
+            data.dao
+                .basic
+                .set_vote(proposal.id, proposer, Some(Votes::Yes(10)))
+                .unwrap();
+            data.dao.pending_proposal.push(proposal.id);
+            block_on(data.dao.check_proposal());
+            data.dao
+                .basic
+                .get_proposal(proposal.id)
+                .unwrap()
+                .proposal_state
+        })
+    }
+
+    #[test]
+    fn membership_proposals_update_the_member_count() {
+        setup(principal(1));
+        test_ledger::set_balance(ic::id(), 100);
+        let members = || ic::with(|data: &Data| data.analytics.analytics(ic::time(), None).members);
+        ic::with_mut(|data: &mut Data| data.dao.join(principal(1), JoinDaoParams::default()))
+            .unwrap();
+        assert_eq!(members(), 1);
+
+        let state = execute_membership_change(principal(3), MembershipAction::Add);
+        assert_eq!(state, ProposalState::Succeeded);
+        assert_eq!(members(), 2);
+        // admitting a member twice does not count them twice
+        execute_membership_change(principal(3), MembershipAction::Add);
+        assert_eq!(members(), 2);
+
+        execute_membership_change(principal(3), MembershipAction::Remove);
+        assert_eq!(members(), 1);
+        assert!(
+            !ic::with(|data: &Data| data.dao.member_list.get(&principal(3)))
+                .unwrap()
+                .is_joined()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::canister::token_ledger::TokenConfig;
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What the deposited funds are for
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum DepositPurpose {
+    MembershipFee,
+    Stake,
+    ProposalDeposit,
+}
+
+/// A subaccount of the DAO canister assigned to one user and purpose
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct DepositAddress {
+    pub owner: Principal,
+    pub purpose: DepositPurpose,
+    pub token: String,
+    pub subaccount: Subaccount,
+    /// Ledger account identifier of the subaccount, for the ICP ledger and EXT tokens
+    pub account_id: String,
+    pub created_at: u64,
+    /// Balance of the subaccount already credited to the owner
+    pub credited: u128,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct DepositCredit {
+    pub purpose: DepositPurpose,
+    pub token: String,
+    pub amount: u128,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct DepositService {
+    #[serde(default)]
+    pub addresses: Vec<DepositAddress>,
+    #[serde(default)]
+    pub credits: HashMap<Principal, Vec<DepositCredit>>,
+}
+
+impl DepositService {
+    pub fn find_address(
+        &self,
+        owner: Principal,
+        purpose: DepositPurpose,
+        token: &str,
+    ) -> Option<DepositAddress> {
+        self.addresses
+            .iter()
+            .find(|item| item.owner == owner && item.purpose == purpose && item.token == token)
+            .cloned()
+    }
+
+    pub fn add_address(
+        &mut self,
+        owner: Principal,
+        purpose: DepositPurpose,
+        token: &TokenConfig,
+        subaccount: Subaccount,
+    ) -> DepositAddress {
+        let address = DepositAddress {
+            owner,
+            purpose,
+            token: token.symbol.clone(),
+            subaccount,
+            account_id: AccountIdentifier::new(&ic::id(), &subaccount).to_string(),
+            created_at: ic::time(),
+            credited: 0,
+        };
+        self.addresses.push(address.clone());
+        address
+    }
+
+    pub fn get_address(
+        &self,
+        owner: Principal,
+        subaccount: Subaccount,
+    ) -> Result<DepositAddress, String> {
+        self.addresses
+            .iter()
+            .find(|item| item.owner == owner && item.subaccount == subaccount)
+            .cloned()
+            .ok_or_else(|| String::from("Deposit address does not exist"))
+    }
+
+    pub fn get_addresses(&self, owner: Principal) -> Vec<DepositAddress> {
+        self.addresses
+            .iter()
+            .filter(|item| item.owner == owner)
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_credits(&self, owner: Principal) -> Vec<DepositCredit> {
+        self.credits.get(&owner).cloned().unwrap_or_default()
+    }
+
+    /// Credit the owner with what the subaccount holds beyond the credited balance,
+    /// returns the newly credited amount
+    pub fn credit(
+        &mut self,
+        subaccount: Subaccount,
+        balance: u128,
+    ) -> Result<DepositCredit, String> {
+        let address = self
+            .addresses
+            .iter_mut()
+            .find(|item| item.subaccount == subaccount)
+            .ok_or_else(|| String::from("Deposit address does not exist"))?;
+        let amount = balance.saturating_sub(address.credited);
+        address.credited += amount;
+
+        let credits = self.credits.entry(address.owner).or_default();
+        match credits
+            .iter_mut()
+            .find(|item| item.purpose == address.purpose && item.token == address.token)
+        {
+            Some(credit) => credit.amount += amount,
+            None => credits.push(DepositCredit {
+                purpose: address.purpose,
+                token: address.token.clone(),
+                amount,
+            }),
+        }
+
+        Ok(DepositCredit {
+            purpose: address.purpose,
+            token: address.token.clone(),
+            amount,
+        })
+    }
+}
@@ -1,9 +1,12 @@
 use crate::canister::ledger;
 use crate::canister::standard_ext::TokenIdentifier;
+use crate::canister::token_ledger::TokenConfig;
+use crate::config::DaoConfig;
+use crate::logger::{LogLevel, LogValue};
 use crate::tools;
 use candid::{CandidType, Principal};
 use ic_ledger_types::{
-    AccountIdentifier as LedgerAccountIdentifier, Memo, Subaccount, DEFAULT_SUBACCOUNT,
+    AccountIdentifier as LedgerAccountIdentifier, BlockIndex, Memo, Subaccount, DEFAULT_SUBACCOUNT,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt;
@@ -13,6 +16,45 @@ use std::{convert::TryFrom, vec};
 pub enum Amount {
     NDP(u64),
     ICP(u64),
+    DIP20(Principal, u64),
+    ICRC1(Principal, u64),
+    /// An amount of a token registered in the DAO config, by symbol
+    Token(String, u64),
+}
+
+impl Amount {
+    pub fn value(&self) -> u64 {
+        match self {
+            Self::NDP(a) | Self::ICP(a) | Self::DIP20(_, a) | Self::ICRC1(_, a) => *a,
+            Self::Token(_, a) => *a,
+        }
+    }
+
+    /// The same token with another amount
+    pub fn with_value(&self, value: u64) -> Amount {
+        match self {
+            Self::NDP(_) => Self::NDP(value),
+            Self::ICP(_) => Self::ICP(value),
+            Self::DIP20(c, _) => Self::DIP20(*c, value),
+            Self::ICRC1(c, _) => Self::ICRC1(*c, value),
+            Self::Token(symbol, _) => Self::Token(symbol.clone(), value),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.value() == 0
+    }
+
+    /// Name of the token, used to aggregate amounts of the same token
+    pub fn token(&self) -> String {
+        match self {
+            Self::NDP(_) => String::from("NDP"),
+            Self::ICP(_) => String::from("ICP"),
+            Self::DIP20(c, _) => format!("{}:{}", "DIP20", c.to_text()),
+            Self::ICRC1(c, _) => format!("{}:{}", "ICRC1", c.to_text()),
+            Self::Token(symbol, _) => symbol.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Amount {
@@ -20,6 +62,9 @@ impl fmt::Display for Amount {
         let s = match &self {
             Self::NDP(a) => format!("{}:{}", "NDP", a),
             Self::ICP(a) => format!("{}:{}", "ICP", a),
+            Self::DIP20(c, a) => format!("{}:{}:{}", "DIP20", c.to_text(), a),
+            Self::ICRC1(c, a) => format!("{}:{}:{}", "ICRC1", c.to_text(), a),
+            Self::Token(symbol, a) => format!("{}:{}", symbol, a),
         };
 
         write!(f, "{}", s)
@@ -36,8 +81,26 @@ pub struct Disbursement {
     pub to_subaccount: Option<Subaccount>,
     pub amount: Amount,
     pub try_num: u8,
+    #[serde(default)]
+    pub proposal_id: Option<u64>,
+    #[serde(default)]
+    pub bounty_id: Option<u64>,
+    #[serde(default)]
+    pub stream_id: Option<u64>,
+    /// Index of the payment in the stream's payments
+    #[serde(default)]
+    pub stream_payment: Option<u64>,
+    /// Budget id and spend id
+    #[serde(default)]
+    pub budget_spend: Option<(u64, u64)>,
+    /// Ledger of an `Amount::Token`, looked up when the disbursement is queued
+    #[serde(default)]
+    pub token: Option<TokenConfig>,
 }
 
+/// Failed disbursements are retried until they have been tried this many times
+pub const MAX_DISBURSEMENT_TRIES: u8 = 5;
+
 #[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
 pub struct DisburseService {
     #[serde(default)]
@@ -75,32 +138,37 @@ impl DisburseService {
         default_subaccount
     }
 
-    pub fn add_disbursement(&mut self, disbursement: Disbursement) -> () {
+    /// Queue a payment, a token amount is paid on the ledger registered at this time
+    pub fn add_disbursement(&mut self, mut disbursement: Disbursement, config: &DaoConfig) {
+        if let Amount::Token(symbol, _) = &disbursement.amount {
+            disbursement.token = config.token(symbol).ok();
+        }
         self.disbursements_queue.push(disbursement);
     }
 
     pub async fn handle_failed_disbursements(
         &mut self,
-    ) -> (Option<Disbursement>, Result<String, String>) {
-        if self.failed_disbursements.is_empty() {
-            return (None, Ok(String::from("")));
-        }
-
-        let disbursement = self.failed_disbursements.pop().unwrap();
+    ) -> Option<(Disbursement, Result<BlockIndex, String>)> {
+        let mut disbursement = self.failed_disbursements.pop()?;
+        disbursement.try_num += 1;
         let r = self.handle_disbursement(disbursement.clone()).await;
 
-        (Some(disbursement), r)
+        if r.is_err() && disbursement.try_num < MAX_DISBURSEMENT_TRIES {
+            self.failed_disbursements.insert(0, disbursement.clone());
+        }
+
+        Some((disbursement, r))
     }
 
     pub async fn handle_pending_disbursements(
         &mut self,
-    ) -> (Option<Disbursement>, Result<String, String>) {
+    ) -> Option<(Disbursement, Result<BlockIndex, String>)> {
         if self.disbursements_queue.is_empty() {
-            return (None, Ok(String::from("")));
+            return None;
         }
 
-        if self.disbursements_process_lock == true {
-            return (None, Ok(String::from("")));
+        if self.disbursements_process_lock {
+            return None;
         };
 
         self.disbursements_process_lock = true;
@@ -115,13 +183,13 @@ impl DisburseService {
 
         self.disbursements_process_lock = false;
 
-        (Some(disbursement), r)
+        Some((disbursement, r))
     }
 
     pub async fn handle_disbursement(
         &mut self,
         disbursement: Disbursement,
-    ) -> Result<String, String> {
+    ) -> Result<BlockIndex, String> {
         let result = match disbursement.amount {
             Amount::ICP(amount) => {
                 let (_, idx) = tools::decode_token(disbursement.token_idf.clone())
@@ -146,62 +214,56 @@ impl DisburseService {
                 )
                 .await
             }
+            Amount::DIP20(canister, amount) => {
+                ledger::dip20_transfer(canister, disbursement.to, amount.into()).await
+            }
+            Amount::ICRC1(canister, amount) => {
+                ledger::icrc1_transfer(
+                    canister,
+                    disbursement.from_subaccount,
+                    disbursement.to,
+                    disbursement.to_subaccount,
+                    amount.into(),
+                    Some(disbursement.token_idf.as_bytes().to_vec()),
+                )
+                .await
+            }
+            Amount::Token(ref symbol, amount) => match &disbursement.token {
+                Some(token) => {
+                    token
+                        .ledger()
+                        .transfer(
+                            disbursement.from_subaccount,
+                            disbursement.to,
+                            disbursement.to_subaccount,
+                            amount as u128,
+                            disbursement.proposal_id.unwrap_or_default(),
+                        )
+                        .await
+                }
+                None => Err(format!("Token {} is not registered", symbol)),
+            },
         };
 
         match result {
-            Ok(_) => {
+            Ok(block) => {
                 tools::log_message(
-                    disbursement.canister.clone(),
+                    LogLevel::Info,
                     ic_cdk::api::id(),
-                    String::from("disburse_ok"),
-                    vec![
-                        (String::from("disbursement"), format!("{:?}", disbursement)),
-                        (
-                            "from".to_string(),
-                            LedgerAccountIdentifier::new(
-                                &ic_cdk::api::id(),
-                                &disbursement.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
-                            )
-                            .to_string(),
-                        ),
-                        (
-                            "to".to_string(),
-                            LedgerAccountIdentifier::new(
-                                &disbursement.to,
-                                &disbursement.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
-                            )
-                            .to_string(),
-                        ),
-                    ],
+                    "disburse_ok",
+                    format!("{:?}", disbursement),
+                    disbursement_log_fields(&disbursement, Some(block), None),
                 );
 
-                Ok(String::from("ok"))
+                Ok(block)
             }
             Err(err) => {
                 tools::log_message(
-                    disbursement.canister.clone(),
+                    LogLevel::Error,
                     ic_cdk::api::id(),
-                    String::from("disburse_err"),
-                    vec![
-                        (String::from("disbursement"), format!("{:?}", disbursement)),
-                        (
-                            "from".to_string(),
-                            LedgerAccountIdentifier::new(
-                                &ic_cdk::api::id(),
-                                &disbursement.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
-                            )
-                            .to_string(),
-                        ),
-                        (
-                            "to".to_string(),
-                            LedgerAccountIdentifier::new(
-                                &disbursement.to,
-                                &disbursement.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
-                            )
-                            .to_string(),
-                        ),
-                        ("err".to_string(), format!("{:#?}", err)),
-                    ],
+                    "disburse_err",
+                    format!("{:?}", disbursement),
+                    disbursement_log_fields(&disbursement, None, Some(&err)),
                 );
 
                 Err(err)
@@ -209,3 +271,52 @@ impl DisburseService {
         }
     }
 }
+
+fn disbursement_log_fields(
+    disbursement: &Disbursement,
+    block: Option<BlockIndex>,
+    error: Option<&String>,
+) -> Vec<(String, LogValue)> {
+    let account = |owner, subaccount: Option<Subaccount>| {
+        LogValue::Text(
+            LedgerAccountIdentifier::new(owner, &subaccount.unwrap_or(DEFAULT_SUBACCOUNT))
+                .to_string(),
+        )
+    };
+    let mut fields = vec![
+        (
+            String::from("canister"),
+            LogValue::Text(disbursement.canister.clone()),
+        ),
+        (
+            String::from("from"),
+            account(&ic_cdk::api::id(), disbursement.from_subaccount),
+        ),
+        (
+            String::from("to"),
+            account(&disbursement.to, disbursement.to_subaccount),
+        ),
+        (
+            String::from("token"),
+            LogValue::Text(disbursement.amount.token()),
+        ),
+        (
+            String::from("amount"),
+            LogValue::Nat(disbursement.amount.value()),
+        ),
+        (
+            String::from("try_num"),
+            LogValue::Nat(disbursement.try_num as u64),
+        ),
+    ];
+    if let Some(id) = disbursement.proposal_id {
+        fields.push((String::from("proposal_id"), LogValue::Nat(id)));
+    }
+    if let Some(block) = block {
+        fields.push((String::from("block_index"), LogValue::Nat(block)));
+    }
+    if let Some(error) = error {
+        fields.push((String::from("error"), LogValue::Text(error.clone())));
+    }
+    fields
+}
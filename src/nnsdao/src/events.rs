@@ -0,0 +1,218 @@
+//! Append-only history of governance actions.
+//!
+//! Every event carries the hash of the one before it, so the tip hash commits to the whole
+//! history. The hash of an event is `sha256(parent_hash | index | timestamp | caller |
+//! candid(kind))`, with the integers little endian, the caller prefixed by its length and
+//! no parent hash for the first event. The tip is part of the certified data, see `certified`.
+
+use crate::disburse::Amount;
+use crate::sdk::{ProposalState, Votes};
+use crate::stable::{StableVec, EVENTS};
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Events returned by a single `get_events` call at most
+const MAX_EVENTS_PER_QUERY: u64 = 2000;
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub enum EventKind {
+    /// The caller of a membership event is the member, also when a proposal admitted or
+    /// removed them
+    Join,
+    Quit,
+    Propose {
+        proposal_id: u64,
+    },
+    Vote {
+        proposal_id: u64,
+        vote: Votes,
+    },
+    WithdrawVote {
+        proposal_id: u64,
+    },
+    ProposalState {
+        proposal_id: u64,
+        state: ProposalState,
+    },
+    Disbursement {
+        proposal_id: Option<u64>,
+        to: Principal,
+        amount: Amount,
+        block_index: Option<u64>,
+        error: Option<String>,
+    },
+    LikeComment {
+        proposal_id: u64,
+        /// Index of the comment in the proposal
+        comment: u64,
+        author: Principal,
+    },
+    AddOwner {
+        principal: Principal,
+    },
+    AddModerator {
+        principal: Principal,
+    },
+    RemoveModerator {
+        principal: Principal,
+    },
+    ImportState,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct Event {
+    pub index: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub kind: EventKind,
+    pub parent_hash: Option<Vec<u8>>,
+    pub hash: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct EventTip {
+    /// Number of events, the index of the next one
+    pub length: u64,
+    pub hash: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct GetEventsResult {
+    pub length: u64,
+    pub events: Vec<Event>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct EventService {
+    /// Kept in stable memory, see `stable`
+    #[serde(skip)]
+    pub events: StableVec<Event, EVENTS>,
+    #[serde(default)]
+    pub tip: Option<Vec<u8>>,
+}
+
+impl EventService {
+    pub fn record(&mut self, caller: Principal, kind: EventKind) -> Event {
+        let index = self.events.len();
+        let timestamp = ic::time();
+        let hash = event_hash(self.tip.as_deref(), index, timestamp, caller, &kind);
+        let event = Event {
+            index,
+            timestamp,
+            caller,
+            kind,
+            parent_hash: self.tip.clone(),
+            hash: hash.clone(),
+        };
+        self.events.push(event.clone());
+        self.tip = Some(hash);
+        event
+    }
+
+    pub fn get_events(&self, start: u64, length: u64) -> GetEventsResult {
+        let end = start
+            .saturating_add(length.min(MAX_EVENTS_PER_QUERY))
+            .min(self.events.len());
+        GetEventsResult {
+            length: self.events.len(),
+            events: (start..end)
+                .filter_map(|index| self.events.get(index))
+                .collect(),
+        }
+    }
+
+    pub fn tip(&self) -> EventTip {
+        EventTip {
+            length: self.events.len(),
+            hash: self.tip.clone(),
+        }
+    }
+}
+
+fn event_hash(
+    parent: Option<&[u8]>,
+    index: u64,
+    timestamp: u64,
+    caller: Principal,
+    kind: &EventKind,
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    if let Some(parent) = parent {
+        hasher.update(parent);
+    }
+    hasher.update(index.to_le_bytes());
+    hasher.update(timestamp.to_le_bytes());
+    let caller = caller.as_slice();
+    hasher.update([caller.len() as u8]);
+    hasher.update(caller);
+    hasher.update(candid::encode_one(kind).expect("Failed to encode event."));
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{principal, setup};
+
+    #[test]
+    fn every_event_hashes_its_parent() {
+        setup(principal(1));
+        let mut service = EventService::default();
+        let first = service.record(principal(1), EventKind::Join);
+        let second = service.record(principal(2), EventKind::Propose { proposal_id: 4 });
+
+        assert_eq!(first.parent_hash, None);
+        let expected = event_hash(None, 0, first.timestamp, principal(1), &EventKind::Join);
+        assert_eq!(first.hash, expected);
+        assert_eq!(second.index, 1);
+        assert_eq!(second.parent_hash, Some(first.hash.clone()));
+        assert_eq!(
+            second.hash,
+            event_hash(
+                Some(&first.hash),
+                1,
+                second.timestamp,
+                principal(2),
+                &second.kind
+            )
+        );
+        let tip = service.tip();
+        assert_eq!(tip.length, 2);
+        assert_eq!(tip.hash, Some(second.hash));
+    }
+
+    #[test]
+    fn hashes_commit_to_every_field() {
+        let base = event_hash(None, 0, 1, principal(1), &EventKind::Join);
+        assert_ne!(
+            base,
+            event_hash(Some(&[0]), 0, 1, principal(1), &EventKind::Join)
+        );
+        assert_ne!(base, event_hash(None, 1, 1, principal(1), &EventKind::Join));
+        assert_ne!(base, event_hash(None, 0, 2, principal(1), &EventKind::Join));
+        assert_ne!(base, event_hash(None, 0, 1, principal(2), &EventKind::Join));
+        assert_ne!(base, event_hash(None, 0, 1, principal(1), &EventKind::Quit));
+    }
+
+    #[test]
+    fn pages_are_bounded_by_the_log() {
+        setup(principal(1));
+        let mut service = EventService::default();
+        for _ in 0..3 {
+            service.record(principal(1), EventKind::Join);
+        }
+        let result = service.get_events(1, 10);
+        assert_eq!(result.length, 3);
+        assert_eq!(
+            result
+                .events
+                .iter()
+                .map(|event| event.index)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(service.get_events(5, 10).events.is_empty());
+    }
+}
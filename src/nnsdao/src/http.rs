@@ -0,0 +1,404 @@
+//! Read-only HTTP interface served through the boundary nodes.
+//!
+//! | path              | content                                  |
+//! |-------------------|------------------------------------------|
+//! | `/dao`            | DAO info                                 |
+//! | `/proposals`      | proposals, newest first                  |
+//! | `/proposals/{id}` | one proposal                             |
+//! | `/members`        | members                                  |
+//! | `/treasury`       | flow totals and recent treasury entries  |
+//!
+//! Lists take `offset` and `limit`. Proposals also take `archived=true`, and `format=rss` or
+//! `format=atom` for a feed instead of JSON.
+//!
+//! Responses are not certified, so they are only served through the `raw` domain
+//! (`https://<canister id>.raw.ic0.app`). Clients that need certified data use the
+//! `get_certified_*` queries instead.
+
+use crate::sdk::Proposal;
+use crate::Data;
+use candid::{CandidType, Deserialize};
+use ic_kit::ic;
+use serde::Serialize;
+use std::collections::HashMap;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+/// Seconds responses may be cached, DAO info changes rarely
+const INFO_MAX_AGE: u64 = 300;
+const LIST_MAX_AGE: u64 = 30;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status_code: u16, content_type: &str, max_age: u64, body: Vec<u8>) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![
+                (String::from("Content-Type"), content_type.to_owned()),
+                (
+                    String::from("Cache-Control"),
+                    format!("public, max-age={}", max_age),
+                ),
+                (
+                    String::from("Access-Control-Allow-Origin"),
+                    String::from("*"),
+                ),
+            ],
+            body,
+        }
+    }
+
+    fn json<T: Serialize>(value: &T, max_age: u64) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::new(200, "application/json; charset=utf-8", max_age, body),
+            Err(err) => Self::error(500, &format!("Failed to serialize: {}", err)),
+        }
+    }
+
+    fn error(status_code: u16, message: &str) -> Self {
+        let body = serde_json::json!({ "error": message })
+            .to_string()
+            .into_bytes();
+        Self::new(status_code, "application/json; charset=utf-8", 0, body)
+    }
+}
+
+#[derive(Serialize)]
+struct Page<T> {
+    total: u64,
+    offset: usize,
+    limit: usize,
+    items: Vec<T>,
+}
+
+struct Query {
+    params: HashMap<String, String>,
+}
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        let params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) => (key.to_owned(), value.to_owned()),
+                None => (pair.to_owned(), String::new()),
+            })
+            .collect();
+        Query { params }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(String::as_str)
+    }
+
+    /// Offset and limit of the requested page
+    fn range(&self) -> (usize, usize) {
+        let offset = self
+            .get("offset")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        let limit = self
+            .get("limit")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_LIMIT)
+            .min(MAX_LIMIT);
+        (offset, limit)
+    }
+
+    fn page<T>(&self, total: u64, items: impl Iterator<Item = T>) -> Page<T> {
+        let (offset, limit) = self.range();
+        let items = items.skip(offset).take(limit).collect();
+        Page {
+            total,
+            offset,
+            limit,
+            items,
+        }
+    }
+}
+
+pub fn handle(data: &Data, request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "Only GET requests are supported");
+    }
+    let (path, query) = request.url.split_once('?').unwrap_or((&request.url, ""));
+    let query = Query::parse(query);
+    let segments: Vec<&str> = path.split('/').filter(|item| !item.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["dao"] => match data.dao.dao_info() {
+            Ok(info) => HttpResponse::json(&info, INFO_MAX_AGE),
+            Err(err) => HttpResponse::error(500, &err),
+        },
+        ["proposals"] => {
+            let (offset, limit) = query.range();
+            let (total, items) =
+                data.dao
+                    .basic
+                    .proposals_page(query.get("archived") == Some("true"), offset, limit);
+            let page = Page {
+                total,
+                offset,
+                limit,
+                items,
+            };
+            let base = base_url();
+            match query.get("format") {
+                Some("rss") => feed_response("application/rss+xml", rss(&base, &page.items)),
+                Some("atom") => feed_response("application/atom+xml", atom(&base, &page.items)),
+                _ => HttpResponse::json(&page, LIST_MAX_AGE),
+            }
+        }
+        ["proposals", id] => match id.parse().map(|id| data.dao.basic.get_proposal(id)) {
+            Ok(Ok(proposal)) => HttpResponse::json(&proposal, LIST_MAX_AGE),
+            _ => HttpResponse::error(404, "Proposal not found"),
+        },
+        ["members"] => {
+            let (offset, limit) = query.range();
+            let (total, items) = data.dao.member_page(offset, limit);
+            let page = Page {
+                total,
+                offset,
+                limit,
+                items,
+            };
+            HttpResponse::json(&page, LIST_MAX_AGE)
+        }
+        ["treasury"] => {
+            let entries = &data.treasury.entries;
+            HttpResponse::json(
+                &serde_json::json!({
+                    "flows": data.treasury.flows,
+                    "entries": query.page(entries.len() as u64, entries.iter().rev()),
+                }),
+                LIST_MAX_AGE,
+            )
+        }
+        _ => HttpResponse::error(404, "Not found"),
+    }
+}
+
+fn feed_response(content_type: &str, body: String) -> HttpResponse {
+    HttpResponse::new(
+        200,
+        &format!("{}; charset=utf-8", content_type),
+        LIST_MAX_AGE,
+        body.into_bytes(),
+    )
+}
+
+/// Links point at the raw domain of this canister, whatever host the request named
+fn base_url() -> String {
+    format!("https://{}.raw.ic0.app", ic::id().to_text())
+}
+
+fn rss(base: &str, proposals: &[Proposal]) -> String {
+    let mut xml =
+        String::from(r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0"><channel>"#);
+    xml.push_str("<title>Proposals</title>");
+    xml.push_str(&format!(
+        "<link>{}</link>",
+        escape_xml(&format!("{}/proposals", base))
+    ));
+    xml.push_str("<description>Latest proposals of the DAO</description>");
+    for proposal in proposals {
+        let link = escape_xml(&format!("{}/proposals/{}", base, proposal.id));
+        xml.push_str(&format!(
+            "<item><title>{}</title><link>{}</link><guid>{}</guid><pubDate>{}</pubDate><description>{}</description></item>",
+            escape_xml(&proposal.title),
+            link,
+            link,
+            rfc822(proposal.timestamp),
+            escape_xml(&proposal.content),
+        ));
+    }
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+fn atom(base: &str, proposals: &[Proposal]) -> String {
+    let updated = proposals
+        .iter()
+        .map(|proposal| proposal.timestamp)
+        .max()
+        .unwrap_or_else(ic::time);
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?><feed xmlns="http://www.w3.org/2005/Atom">"#,
+    );
+    xml.push_str("<title>Proposals</title>");
+    let feed = escape_xml(&format!("{}/proposals", base));
+    xml.push_str(&format!("<id>{}</id>", feed));
+    xml.push_str(&format!(r#"<link href="{}"/>"#, feed));
+    xml.push_str(&format!("<updated>{}</updated>", rfc3339(updated)));
+    for proposal in proposals {
+        let link = escape_xml(&format!("{}/proposals/{}", base, proposal.id));
+        xml.push_str(&format!(
+            r#"<entry><title>{}</title><id>{}</id><link href="{}"/><updated>{}</updated><author><name>{}</name></author><content>{}</content></entry>"#,
+            escape_xml(&proposal.title),
+            link,
+            link,
+            rfc3339(proposal.timestamp),
+            proposal.proposer.to_text(),
+            escape_xml(&proposal.content),
+        ));
+    }
+    xml.push_str("</feed>");
+    xml
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Calendar date and time in UTC of a timestamp in nanoseconds
+struct DateTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    /// Days since the epoch, a Thursday
+    days: i64,
+}
+
+impl DateTime {
+    fn from_nanos(nanos: u64) -> Self {
+        let seconds = nanos / 1_000_000_000;
+        let days = (seconds / 86400) as i64;
+        let rest = seconds % 86400;
+        // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: rest / 3600,
+            minute: rest % 3600 / 60,
+            second: rest % 60,
+            days,
+        }
+    }
+}
+
+fn rfc822(nanos: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let time = DateTime::from_nanos(nanos);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[time.days.rem_euclid(7) as usize],
+        time.day,
+        MONTHS[time.month as usize - 1],
+        time.year,
+        time.hour,
+        time.minute,
+        time.second
+    )
+}
+
+fn rfc3339(nanos: u64) -> String {
+    let time = DateTime::from_nanos(nanos);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        time.year, time.month, time.day, time.hour, time.minute, time.second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{principal, setup};
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn dates_are_formatted_in_utc() {
+        assert_eq!(rfc822(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            rfc822(1_709_210_096 * SECOND + 999),
+            "Thu, 29 Feb 2024 12:34:56 GMT"
+        );
+        assert_eq!(rfc3339(1_709_210_096 * SECOND), "2024-02-29T12:34:56Z");
+        assert_eq!(
+            rfc822(951_868_800 * SECOND),
+            "Wed, 01 Mar 2000 00:00:00 GMT"
+        );
+        assert_eq!(rfc3339(4_133_980_799 * SECOND), "2100-12-31T23:59:59Z");
+        assert_eq!(
+            rfc822(4_133_980_799 * SECOND),
+            "Fri, 31 Dec 2100 23:59:59 GMT"
+        );
+    }
+
+    #[test]
+    fn feed_text_is_escaped() {
+        assert_eq!(
+            escape_xml(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &apos;Jerry&apos;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn feed_links_point_at_the_canister() {
+        setup(principal(1));
+        let request = HttpRequest {
+            method: String::from("GET"),
+            url: String::from("/proposals?format=rss"),
+            headers: vec![(String::from("Host"), String::from("evil.example\"><x>"))],
+            body: vec![],
+        };
+        let response = ic::with(|data: &Data| handle(data, request));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains(&format!("{}/proposals</link>", base_url())));
+        assert!(!body.contains("evil"));
+    }
+
+    #[test]
+    fn pages_are_limited() {
+        let query = Query::parse("offset=2&limit=1000&archived");
+        assert_eq!(query.range(), (2, MAX_LIMIT));
+        assert_eq!(query.get("archived"), Some(""));
+        let page = Query::parse("offset=1&limit=2").page(4, vec![1, 2, 3, 4].into_iter());
+        assert_eq!(page.total, 4);
+        assert_eq!(page.items, vec![2, 3]);
+        assert_eq!(Query::parse("").range(), (0, DEFAULT_LIMIT));
+    }
+}
@@ -1,13 +1,20 @@
-use crate::Data;
+use crate::{stable, tools, Data};
 use candid::Principal;
 use ic_cdk_macros::init;
 use ic_kit::ic;
 
 #[init]
+#[candid::candid_method(init)]
 fn init(owner: Principal) {
     ic_cdk::setup();
-    let data = ic::get_mut::<Data>();
-    data.owners.add_owner(owner);
+    stable::initialize();
+    ic::with_mut(|data: &mut Data| {
+        data.owners.add_owner(owner);
+        data.run_heartbeat = true;
+        data.heartbeat_interval_seconds = 2;
+    });
+    tools::rebuild_certified();
+    tools::rebuild_analytics();
 
     // if let Ok(..) = data.dao.join(
     //     owner,
@@ -1,17 +1,46 @@
+mod analytics;
+mod backup;
+mod bounty;
+mod budget;
 mod canister;
+mod certified;
+mod config;
 mod dao;
+mod deposit;
 mod disburse;
+mod events;
+mod http;
 mod init;
 mod logger;
 mod owner;
+mod proposal;
+mod reputation;
 pub mod sdk;
+mod stable;
+mod stream;
+#[cfg(test)]
+mod test_utils;
 mod tools;
+mod treasury;
 pub mod types;
-
+mod upgrade;
+
+use crate::analytics::{AnalyticsService, DaoAnalytics, MemberParticipation};
+use crate::backup::{BackupInfo, BackupService};
+use crate::bounty::{Bounty, BountyService};
+use crate::budget::{Budget, BudgetService, SpendArg};
+use crate::certified::{Certified, CertifiedService};
+use crate::config::DaoConfig;
+use crate::deposit::{DepositAddress, DepositCredit, DepositPurpose, DepositService};
+use crate::events::{EventKind, EventService, EventTip, GetEventsResult};
+use crate::http::{HttpRequest, HttpResponse};
 use crate::logger::*;
 use crate::owner::*;
+use crate::reputation::{MemberReputation, ReputationService};
 use crate::sdk::Comment;
+use crate::sdk::EditProposalArg;
 use crate::sdk::Proposal;
+use crate::stream::{Stream, StreamService};
 use crate::tools::canister_status;
 use crate::types::DaoData;
 
@@ -22,25 +51,26 @@ use dao::ProposalBody;
 use dao::ProposalContent;
 use dao::UserVoteArgs;
 use dao::{DaoService, MemberItems};
-use disburse::DisburseService;
+use disburse::{DisburseService, Disbursement, MAX_DISBURSEMENT_TRIES};
 use ic_cdk::api::management_canister::main::{update_settings, UpdateSettingsArgument};
 use ic_cdk::api::management_canister::provisional::CanisterSettings;
 use ic_kit::interfaces::management::CanisterStatusResponse;
 
-use ic_cdk::api::stable::{StableReader, StableWriter};
 use ic_cdk_macros::*;
 use ic_kit::ic;
 
 use ic_kit::RejectionCode;
+use ic_ledger_types::{BlockIndex, Subaccount};
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::Read;
+use std::convert::TryFrom;
 use std::vec::Vec;
 use tools::is_owner;
+use treasury::{FlowDirection, FlowKind, PayoutReport, TreasuryOverview, TreasuryService};
 
 // #[derive(Default, Clone)]
-#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+#[derive(Deserialize, Serialize, Default, Debug)]
 pub struct Data {
     #[serde(default)]
     pub owners: OwnerService,
@@ -55,10 +85,33 @@ pub struct Data {
     pub heartbeat_last_beat: u64,
     #[serde(default)]
     pub heartbeat_interval_seconds: u64,
+    /// When the heartbeat still waiting on other canisters started
+    #[serde(skip)]
+    pub heartbeat_started: Option<u64>,
     #[serde(default)]
     pub disburse: DisburseService,
+    #[serde(default)]
+    pub treasury: TreasuryService,
+    #[serde(default)]
+    pub bounty: BountyService,
+    #[serde(default)]
+    pub stream: StreamService,
+    #[serde(default)]
+    pub budget: BudgetService,
+    #[serde(default)]
+    pub deposit: DepositService,
+    #[serde(default)]
+    pub events: EventService,
+    #[serde(default)]
+    pub certified: CertifiedService,
+    #[serde(default)]
+    pub analytics: AnalyticsService,
+    #[serde(default)]
+    pub reputation: ReputationService,
 }
 
+/// Layout written by `pre_upgrade` before the state was versioned, see `upgrade`. It has to
+/// stay as it was, new state goes into `Data`.
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct DataV0 {
     #[serde(default)]
@@ -77,81 +130,121 @@ pub struct DataV0 {
 #[update]
 #[candid::candid_method]
 fn join(user_info: JoinDaoParams) -> Result<MemberItems, String> {
-    let data = ic::get_mut::<Data>();
-    let caller = ic_cdk::caller();
-    data.dao.join(caller, user_info)
+    tools::logged("join", move || {
+        ic::with_mut(|data: &mut Data| data.dao.join(ic::caller(), user_info))
+    })
 }
 
 #[update]
 #[candid::candid_method]
 pub async fn comment_proposal(id: u64, comment: Comment) -> Result<Proposal, String> {
-    let data = ic::get_mut::<Data>();
-    data.dao.basic.comment(id, comment)
+    tools::logged_async("comment_proposal", async move {
+        ic::with_mut(|data: &mut Data| data.dao.basic.comment(id, ic::caller(), comment))
+    })
+    .await
+}
+
+#[update]
+#[candid::candid_method]
+fn like_comment(proposal_id: u64, comment: u64) -> Result<(), String> {
+    tools::logged("like_comment", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            data.dao.is_member(caller)?;
+            let author = data.dao.basic.like_comment(proposal_id, comment, caller)?;
+            tools::record_event(
+                caller,
+                EventKind::LikeComment {
+                    proposal_id,
+                    comment,
+                    author,
+                },
+            );
+            Ok(())
+        })
+    })
 }
 
 #[query]
 #[candid::candid_method]
 fn member_list() -> Result<Vec<MemberItems>, String> {
-    let data = ic::get::<Data>();
-    data.dao.member_list()
+    ic::with(|data: &Data| data.dao.member_list())
 }
 
 #[update]
 #[candid::candid_method]
 async fn dao_data() -> Result<DaoData, String> {
-    let data = ic::get::<Data>();
-    let info = data.dao.dao_info()?;
-    let member_list = data.dao.member_list()?;
-    let owners = data
-        .owners
-        .get_owners()
-        .iter()
-        .map(|el| el.to_text())
-        .collect();
-    let status = canister_status().await?;
-    let res = DaoData {
-        info,
-        owners,
-        status,
-        member_list,
-    };
-    Ok(res)
+    tools::logged_errors_async("dao_data", async move {
+        let (info, member_list, owners) = ic::with(|data: &Data| {
+            let owners = data
+                .owners
+                .get_owners()
+                .iter()
+                .map(|el| el.to_text())
+                .collect();
+            Ok::<_, String>((data.dao.dao_info()?, data.dao.member_list()?, owners))
+        })?;
+        let status = canister_status().await?;
+        let res = DaoData {
+            info,
+            owners,
+            status,
+            member_list,
+        };
+        Ok(res)
+    })
+    .await
 }
 
 #[query]
 #[candid::candid_method]
 fn dao_info() -> Result<dao::DaoInfo, String> {
-    let data = ic::get::<Data>();
-    data.dao.dao_info()
+    ic::with(|data: &Data| data.dao.dao_info())
 }
 
 #[update]
 #[candid::candid_method]
 async fn dao_status() -> Result<CanisterStatusResponse, String> {
-    canister_status().await
+    tools::logged_errors_async("dao_status", async move { canister_status().await }).await
 }
 
 #[update(guard = "is_owner")]
 #[candid::candid_method]
 fn update_dao_info(dao_info: DaoInfo) -> Result<DaoInfo, String> {
-    let data = ic::get_mut::<Data>();
-    data.dao.update_dao_info(dao_info)
+    tools::logged("update_dao_info", move || {
+        ic::with_mut(|data: &mut Data| data.dao.update_dao_info(dao_info))
+    })
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn dao_config() -> DaoConfig {
+    ic::with(|data: &Data| data.dao.dao_config())
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn update_dao_config(config: DaoConfig) -> Result<DaoConfig, String> {
+    tools::logged("update_dao_config", move || {
+        ic::with_mut(|data: &mut Data| data.dao.update_dao_config(config))
+    })
 }
 
 #[update]
 #[candid::candid_method]
 fn user_info() -> Result<MemberItems, String> {
-    let data = ic::get_mut::<Data>();
-    data.dao.user_info()
+    let result = ic::with_mut(|data: &mut Data| data.dao.user_info());
+    tools::log_error("user_info", &result);
+    result
 }
 
 #[update(guard = "is_owner")]
 #[candid::candid_method(update)]
 async fn update_controller(principal_text: String) -> Result<(), (RejectionCode, String)> {
-    let mut owners = ic::get::<Data>().owners.get_owners();
+    let mut owners = ic::with(|data: &Data| data.owners.get_owners());
     owners.push(Principal::from_text(principal_text).expect("Could not decode the principal."));
-    update_settings(UpdateSettingsArgument {
-        canister_id: ic_cdk::id(),
+    let result = update_settings(UpdateSettingsArgument {
+        canister_id: ic::id(),
         settings: CanisterSettings {
             controllers: Some(owners),
             compute_allocation: None,
@@ -159,37 +252,127 @@ async fn update_controller(principal_text: String) -> Result<(), (RejectionCode,
             freezing_threshold: None,
         },
     })
-    .await?;
-    Ok(())
+    .await;
+    tools::log_result(
+        "update_controller",
+        &result.clone().map_err(|(_, reason)| reason),
+    );
+    result
+}
+
+#[update]
+#[candid::candid_method]
+async fn treasury_overview(offset: u64, limit: u64) -> TreasuryOverview {
+    let data = tools::state();
+    let tokens = data.dao.dao_config().tokens;
+    data.treasury
+        .overview(&tokens, offset as usize, limit as usize)
+        .await
+}
+
+#[update]
+#[candid::candid_method]
+fn request_deposit_address(
+    purpose: DepositPurpose,
+    token: String,
+) -> Result<DepositAddress, String> {
+    tools::logged("request_deposit_address", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            if let Some(address) = data.deposit.find_address(caller, purpose, &token) {
+                return Ok(address);
+            }
+            let token = data.dao.dao_config().token(&token)?;
+            if !token.supports_subaccounts() {
+                return Err(format!(
+                    "{} deposits do not support subaccounts",
+                    token.symbol
+                ));
+            }
+            let subaccount = data.disburse.get_transaction_subaccount();
+            data.treasury.track_subaccount(subaccount, &token.symbol);
+            Ok(data
+                .deposit
+                .add_address(caller, purpose, &token, subaccount))
+        })
+    })
+}
+
+#[update]
+#[candid::candid_method]
+async fn notify_deposit(subaccount: Subaccount) -> Result<DepositCredit, String> {
+    tools::logged_async("notify_deposit", async move {
+        let data = tools::state();
+        let caller = ic::caller();
+        let address = data.deposit.get_address(caller, subaccount)?;
+        let token = data.dao.dao_config().token(&address.token)?;
+        let balance = token
+            .ledger()
+            .balance_of(ic::id(), Some(subaccount))
+            .await?;
+        // credited again after the call, concurrent notifications only credit once
+        let credit = data.deposit.credit(subaccount, balance)?;
+        if credit.amount > 0 {
+            tools::record_token_flow(
+                FlowDirection::Inflow,
+                FlowKind::Deposit,
+                &token.symbol,
+                u64::try_from(credit.amount).unwrap_or(u64::MAX),
+                caller,
+                None,
+            );
+        }
+        Ok(credit)
+    })
+    .await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_deposit_addresses() -> Vec<DepositAddress> {
+    ic::with(|data: &Data| data.deposit.get_addresses(ic::caller()))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_deposit_credits() -> Vec<DepositCredit> {
+    ic::with(|data: &Data| data.deposit.get_credits(ic::caller()))
 }
 
 #[update]
 #[candid::candid_method]
 fn quit() -> Result<MemberItems, String> {
-    let data = ic::get_mut::<Data>();
-    let caller = ic_cdk::caller();
-    data.dao.quit(caller)
+    tools::logged("quit", move || {
+        ic::with_mut(|data: &mut Data| data.dao.quit(ic::caller()))
+    })
 }
 
 #[update(guard = "is_owner")]
 #[candid::candid_method]
 fn add_owner(principal: Principal) -> Vec<Principal> {
-    let data = ic::get_mut::<Data>();
-    let caller = ic_cdk::caller();
-    data.owners.add_owner(principal)
+    tools::log_call("add_owner");
+    ic::with_mut(|data: &mut Data| {
+        let caller = ic::caller();
+        tools::record_event(caller, EventKind::AddOwner { principal });
+        data.owners.add_owner(principal)
+    })
 }
 
 #[query]
 #[candid::candid_method(query)]
 fn get_owners() -> Vec<Principal> {
-    let data = ic::get::<Data>();
-    data.owners.get_owners()
+    ic::with(|data: &Data| data.owners.get_owners())
 }
 #[query]
 #[candid::candid_method(query)]
 fn get_proposal_list() -> Result<HashMap<u64, Proposal>, String> {
-    let data = ic::get::<Data>();
-    Ok(data.dao.basic.proposal_list())
+    ic::with(|data: &Data| Ok(data.dao.basic.proposal_list()))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_proposals(include_archived: bool) -> Vec<Proposal> {
+    ic::with(|data: &Data| data.dao.basic.proposals(include_archived))
 }
 
 // #[update]
@@ -211,106 +394,479 @@ fn get_proposal_list() -> Result<HashMap<u64, Proposal>, String> {
 // #[query]
 // #[candid::candid_method(query)]
 // fn get_pay_address() -> Result<String, String> {
-//     let data = ic::get_mut::<Data>();
+//     let data = tools::state();
 //     let transaction_subaccount = data.disburse.get_transaction_subaccount();
-//     let payment_address = AccountIdentifier::new(&ic_cdk::api::id(), &transaction_subaccount);
+//     let payment_address = AccountIdentifier::new(&ic::id(), &transaction_subaccount);
 //     Ok(payment_address.to_string())
 // }
 
 #[update]
 #[candid::candid_method]
 async fn propose(arg: ProposalContent) -> Result<Proposal, String> {
-    let data = ic::get_mut::<Data>();
-
-    data.dao
-        .propose(ProposalBody {
-            proposer: ic_cdk::caller(),
-            title: arg.title,
-            content: arg.content,
-            start_time: arg.start_time,
-            end_time: arg.end_time,
-            property: arg.property,
-        })
-        .await
+    tools::logged_async("propose", async move {
+        let data = tools::state();
+
+        let proposal = data
+            .dao
+            .propose(ProposalBody {
+                proposer: ic::caller(),
+                title: arg.title,
+                content: arg.content,
+                start_time: arg.start_time,
+                end_time: arg.end_time,
+                property: arg.property,
+                payload: arg.payload.unwrap_or_default(),
+            })
+            .await?;
+        tools::record_event(
+            proposal.proposer,
+            EventKind::Propose {
+                proposal_id: proposal.id,
+            },
+        );
+        Ok(proposal)
+    })
+    .await
 }
 
 #[query]
 #[candid::candid_method(query)]
 fn get_proposal(id: u64) -> Result<Proposal, String> {
-    let data = ic::get::<Data>();
-    data.dao.basic.get_proposal(id)
+    ic::with(|data: &Data| data.dao.basic.get_proposal(id))
 }
 
 #[update]
 #[candid::candid_method(update)]
 async fn vote(arg: UserVoteArgs) -> Result<(), String> {
-    let data = ic::get_mut::<Data>();
-    data.dao.vote(arg).await
+    tools::logged_async("vote", async move {
+        let data = tools::state();
+        let kind = EventKind::Vote {
+            proposal_id: arg.id,
+            vote: arg.vote.clone(),
+        };
+        data.dao.vote(arg).await?;
+        tools::record_event(ic::caller(), kind);
+        Ok(())
+    })
+    .await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn edit_proposal(arg: EditProposalArg) -> Result<Proposal, String> {
+    tools::logged_async("edit_proposal", async move {
+        let data = tools::state();
+        let caller = ic::caller();
+        data.dao.edit_proposal(caller, arg).await
+    })
+    .await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn cancel_proposal(id: u64) -> Result<Proposal, String> {
+    tools::logged_async("cancel_proposal", async move {
+        let data = tools::state();
+        let caller = ic::caller();
+        data.dao.cancel_proposal(caller, id).await
+    })
+    .await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn add_moderator(principal: Principal) -> Vec<Principal> {
+    tools::log_call("add_moderator");
+    ic::with_mut(|data: &mut Data| data.dao.add_moderator(principal))
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn remove_moderator(principal: Principal) -> Vec<Principal> {
+    tools::log_call("remove_moderator");
+    ic::with_mut(|data: &mut Data| data.dao.remove_moderator(principal))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_proposal_payouts(id: u64) -> Vec<PayoutReport> {
+    ic::with(|data: &Data| data.dao.get_proposal_payouts(id))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_moderators() -> Vec<Principal> {
+    ic::with(|data: &Data| data.dao.get_moderators())
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn withdraw_vote(id: u64) -> Result<(), String> {
+    tools::logged_async("withdraw_vote", async move {
+        let data = tools::state();
+        data.dao.withdraw_vote(id).await?;
+        tools::record_event(ic::caller(), EventKind::WithdrawVote { proposal_id: id });
+        Ok(())
+    })
+    .await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_bounty(id: u64) -> Result<Bounty, String> {
+    ic::with(|data: &Data| data.bounty.get_bounty(id))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_bounty_list() -> Vec<Bounty> {
+    ic::with(|data: &Data| data.bounty.bounty_list())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_stream(id: u64) -> Result<Stream, String> {
+    ic::with(|data: &Data| data.stream.get_stream(id))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_stream_list() -> Vec<Stream> {
+    ic::with(|data: &Data| data.stream.stream_list())
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_budget(id: u64) -> Result<Budget, String> {
+    ic::with(|data: &Data| data.budget.get_budget(id))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_budget_list(group: Option<String>) -> Vec<Budget> {
+    ic::with(|data: &Data| data.budget.budget_list(group))
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn spend_budget(arg: SpendArg) -> Result<Budget, String> {
+    tools::logged("spend_budget", move || {
+        ic::with_mut(|data: &mut Data| {
+            let budget_id = arg.budget_id;
+            let disbursement = data.budget.spend(ic::caller(), arg)?;
+            data.disburse
+                .add_disbursement(disbursement, &data.dao.dao_config());
+            data.budget.get_budget(budget_id)
+        })
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn claim_bounty(id: u64) -> Result<Bounty, String> {
+    tools::logged("claim_bounty", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            data.dao.is_member(caller)?;
+            data.bounty.claim(caller, id)
+        })
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn unclaim_bounty(id: u64) -> Result<Bounty, String> {
+    tools::logged("unclaim_bounty", move || {
+        ic::with_mut(|data: &mut Data| data.bounty.unclaim(ic::caller(), id))
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn submit_bounty_work(id: u64, work_links: Vec<String>) -> Result<Bounty, String> {
+    tools::logged("submit_bounty_work", move || {
+        ic::with_mut(|data: &mut Data| data.bounty.submit(ic::caller(), id, work_links))
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn approve_bounty(id: u64) -> Result<Bounty, String> {
+    tools::logged("approve_bounty", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            let is_moderator = data.dao.is_moderator(caller);
+            let (bounty, disbursement) = data.bounty.approve(caller, id, is_moderator)?;
+            data.disburse
+                .add_disbursement(disbursement, &data.dao.dao_config());
+            Ok(bounty)
+        })
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn reject_bounty_work(id: u64, reason: String) -> Result<Bounty, String> {
+    tools::logged("reject_bounty_work", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            let is_moderator = data.dao.is_moderator(caller);
+            data.bounty.reject(caller, id, reason, is_moderator)
+        })
+    })
+}
+
+#[update]
+#[candid::candid_method(update)]
+fn cancel_bounty(id: u64) -> Result<Bounty, String> {
+    tools::logged("cancel_bounty", move || {
+        ic::with_mut(|data: &mut Data| {
+            let caller = ic::caller();
+            if !data.dao.is_moderator(caller) {
+                return Err(String::from("Only moderators can cancel bounties"));
+            }
+            data.bounty.cancel(caller, id)
+        })
+    })
 }
 
 #[query]
 #[candid::candid_method(query)]
 pub async fn get_handled_proposal() -> Vec<(u64, Result<String, String>)> {
-    let data = ic::get_mut::<Data>();
+    let data = tools::state();
     data.dao.check_proposal().await;
     data.dao.get_handled_proposal()
 }
-// // heartbeat: 1s
-// #[heartbeat]
-// async fn heartbeat() {
-//     let data = ic::get_mut::<Data>();
-//     // Limit heartbeats
-//     let now = ic_cdk::api::time();
-//     if now - data.heartbeat_last_beat < 5 * 1_000_000_000 {
-//         return;
-//     }
-//     data.heartbeat_last_beat = now;
-//     data.dao.check_proposal().await;
-//     // log
-
-//     // check proposal expire time
-
-//     // ic_cdk::println!("check proposal expire time : {:?}",'');
-// }
+/// Longest a heartbeat may wait on other canisters before the next one runs
+const HEARTBEAT_LOCK_SECONDS: u64 = 10 * 60;
+
+// heartbeat: 1s
+#[heartbeat]
+async fn heartbeat() {
+    let data = tools::state();
+    if !data.run_heartbeat {
+        return;
+    }
+    // Limit heartbeats
+    let now = ic::time();
+    if now - data.heartbeat_last_beat < data.heartbeat_interval_seconds * 1_000_000_000 {
+        return;
+    }
+    // one beat at a time, a beat that trapped while waiting is given up after a while
+    if let Some(started) = data.heartbeat_started {
+        if now - started < HEARTBEAT_LOCK_SECONDS * 1_000_000_000 {
+            return;
+        }
+    }
+    data.heartbeat_started = Some(now);
+    data.heartbeat_last_beat = now;
+    // check proposal expire time
+    data.dao.check_proposal().await;
+    data.dao.archive_proposals(now);
+    // queue vested stream payments
+    for disbursement in data.stream.release_due(now) {
+        data.disburse
+            .add_disbursement(disbursement, &data.dao.dao_config());
+    }
+
+    if let Some((disbursement, result)) = data.disburse.handle_pending_disbursements().await {
+        settle_disbursement(&disbursement, &result);
+    }
+    if let Some((disbursement, result)) = data.disburse.handle_failed_disbursements().await {
+        settle_disbursement(&disbursement, &result);
+    }
+    data.heartbeat_started = None;
+}
+
+fn settle_disbursement(disbursement: &Disbursement, result: &Result<BlockIndex, String>) {
+    ic::with_mut(|data: &mut Data| {
+        if result.is_ok() {
+            tools::record_treasury_flow(
+                FlowDirection::Outflow,
+                FlowKind::Disbursement,
+                disbursement.amount.clone(),
+                disbursement.to,
+                disbursement.proposal_id,
+            );
+        }
+        if result.is_ok() || disbursement.try_num >= MAX_DISBURSEMENT_TRIES {
+            tools::record_event(
+                ic::id(),
+                EventKind::Disbursement {
+                    proposal_id: disbursement.proposal_id,
+                    to: disbursement.to,
+                    amount: disbursement.amount.clone(),
+                    block_index: result.as_ref().ok().copied(),
+                    error: result.as_ref().err().cloned(),
+                },
+            );
+        }
+        if disbursement.bounty_id.is_some() {
+            data.bounty.settle(disbursement, result);
+        } else if disbursement.stream_id.is_some() {
+            data.stream.settle(disbursement, result);
+        } else if disbursement.budget_spend.is_some() {
+            data.budget.settle(disbursement, result);
+        } else {
+            data.dao.settle_treasury_transfer(disbursement, result);
+        }
+    })
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn export_state() -> Result<BackupInfo, String> {
+    tools::logged("export_state", move || {
+        ic::with(|data: &Data| ic::with_mut(|backup: &mut BackupService| backup.export(data)))
+    })
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn export_state_chunk(index: u64) -> Result<Vec<u8>, String> {
+    ic::with(|backup: &BackupService| backup.export_chunk(index))
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn import_state_begin(info: BackupInfo) -> Result<(), String> {
+    tools::logged("import_state_begin", move || {
+        ic::with_mut(|backup: &mut BackupService| backup.begin_import(info))
+    })
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn import_state_chunk(index: u64, chunk: Vec<u8>) -> Result<(), String> {
+    tools::logged("import_state_chunk", move || {
+        ic::with_mut(|backup: &mut BackupService| backup.import_chunk(index, chunk))
+    })
+}
+
+/// Replace the state with the imported one, the caller stays an owner
+#[update(guard = "is_owner")]
+#[candid::candid_method]
+fn import_state_commit() -> Result<(), String> {
+    tools::logged("import_state_commit", move || {
+        let caller = ic::caller();
+        let (mut data, dropped) = ic::with(|data: &Data| {
+            ic::with_mut(|backup: &mut BackupService| backup.commit_import(data))
+        })?;
+        if data.owners.is_owner(caller).is_err() {
+            data.owners.add_owner(caller);
+        }
+        ic::swap(data);
+        tools::restore_derived_state();
+        tools::record_event(caller, EventKind::ImportState);
+        let error = Err(String::from("Dropped when the state was imported"));
+        for disbursement in dropped {
+            settle_disbursement(&disbursement, &error);
+        }
+        Ok(())
+    })
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn get_log_messages(param: GetLogMessagesParameters) -> CanisterLogMessages {
+    ic::with(|data: &Data| data.logger.get_log_messages(param))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_events(start: u64, length: u64) -> GetEventsResult {
+    ic::with(|data: &Data| data.events.get_events(start, length))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_event_tip() -> Certified<EventTip> {
+    ic::with(|data: &Data| data.certified.event_tip(data.events.tip()))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_certified_dao_info() -> Result<Certified<DaoInfo>, String> {
+    ic::with(|data: &Data| Ok(data.certified.dao_info(data.dao.dao_info()?)))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_certified_proposal(id: u64) -> Result<Certified<Proposal>, String> {
+    ic::with(|data: &Data| Ok(data.certified.proposal(data.dao.basic.get_proposal(id)?)))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_certified_proposals(include_archived: bool) -> Certified<Vec<Proposal>> {
+    ic::with(|data: &Data| {
+        data.certified
+            .proposals(data.dao.basic.proposals(include_archived))
+    })
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_certified_member_list() -> Result<Certified<Vec<MemberItems>>, String> {
+    ic::with(|data: &Data| Ok(data.certified.members(data.dao.member_list()?)))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn dao_analytics(active_window_days: Option<u64>) -> DaoAnalytics {
+    ic::with(|data: &Data| data.analytics.analytics(ic::time(), active_window_days))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn member_participation(principal: Principal) -> MemberParticipation {
+    ic::with(|data: &Data| data.analytics.participation(principal))
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn get_reputation(principal: Principal) -> MemberReputation {
+    ic::with(|data: &Data| {
+        let config = data.dao.dao_config().reputation;
+        data.reputation.reputation(principal, ic::time(), &config)
+    })
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn reputation_leaderboard(limit: u64) -> Vec<MemberReputation> {
+    ic::with(|data: &Data| {
+        let config = data.dao.dao_config().reputation;
+        data.reputation
+            .leaderboard(limit as usize, ic::time(), &config)
+    })
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    ic::with(|data: &Data| http::handle(data, request))
+}
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let data = ic::get::<Data>();
-    let writer = StableWriter::default();
-    serde_cbor::to_writer(
-        writer,
-        &DataV0 {
-            disburse: data.disburse.clone(),
-            owners: data.owners.clone(),
-            logger: data.logger.clone(),
-            dao: data.dao.clone(),
-        },
-    )
-    .expect("Failed to serialize data.");
+    ic::with(|data: &Data| {
+        if let Err(err) = upgrade::save_stable_state(data) {
+            ic_cdk::trap(&err);
+        }
+    })
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let reader = StableReader::default();
-
-    let data: DataV0 = match serde_cbor::from_reader(reader) {
-        Ok(t) => t,
-        Err(err) => {
-            let limit = err.offset() - 1;
-            let reader = StableReader::default().take(limit);
-            serde_cbor::from_reader(reader).expect("Failed to deserialize.")
+    // trapping rolls the upgrade back and keeps the current state
+    match upgrade::load_stable_state() {
+        Ok(data) => {
+            ic::swap(data);
+            tools::restore_derived_state();
         }
-    };
-
-    ic::store(Data {
-        owners: data.owners,
-        logger: data.logger,
-        dao: data.dao,
-        disburse: data.disburse,
-        run_heartbeat: true,
-        heartbeat_last_beat: 0,
-        heartbeat_interval_seconds: 2,
-    });
+        Err(err) => ic_cdk::trap(&format!("Refusing to upgrade: {}", err)),
+    }
 }
 
 candid::export_service!();
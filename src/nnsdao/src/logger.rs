@@ -1,19 +1,53 @@
+use crate::stable::{StableVec, LOGS};
+use ic_cdk::export::candid::CandidType;
 use ic_cdk::export::Principal;
-use ic_cdk::{api, export::candid::CandidType};
+use ic_kit::ic;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::convert::TryInto;
 
+#[derive(
+    Debug, CandidType, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+/// Typed value of a log field
+#[derive(Debug, CandidType, Serialize, Deserialize, Clone)]
+pub enum LogValue {
+    Text(String),
+    Nat(u64),
+    Int(i64),
+    Bool(bool),
+    Principal(Principal),
+}
+
 #[allow(non_snake_case)]
 #[derive(Debug, CandidType, Serialize, Deserialize, Clone)]
 pub struct LogMessageData {
     pub timeNanos: u64,
     pub message: String,
+    #[serde(default)]
+    pub level: LogLevel,
+    /// Not known for messages logged before levels were added
+    #[serde(default)]
+    pub caller: Option<Principal>,
+    #[serde(default)]
+    pub method: String,
+    #[serde(default)]
+    pub fields: Vec<(String, LogValue)>,
 }
 
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct LoggerService {
-    pub queue: Vec<LogMessageData>,
+    /// Kept in stable memory, see `stable`
+    #[serde(skip)]
+    pub queue: StableVec<LogMessageData, LOGS>,
     pub max_count: usize,
     pub next: usize,
     pub full: bool,
@@ -32,6 +66,10 @@ pub struct GetLogMessagesParameters {
 pub struct GetLogMessagesFilter {
     pub messageContains: Option<String>,
     pub messageRegex: Option<String>,
+    /// Only messages of this level or above
+    pub minLevel: Option<LogLevel>,
+    pub method: Option<String>,
+    pub caller: Option<Principal>,
 }
 
 #[allow(non_snake_case)]
@@ -44,7 +82,7 @@ pub struct CanisterLogMessages {
 impl Default for LoggerService {
     fn default() -> Self {
         Self {
-            queue: Vec::new(),
+            queue: Default::default(),
             max_count: 20000,
             next: 0,
             full: false,
@@ -55,7 +93,7 @@ impl Default for LoggerService {
 impl LoggerService {
     pub fn store_log_message(&mut self, log_message: LogMessageData) {
         if self.full {
-            self.queue[self.next] = log_message;
+            self.queue.set(self.next as u64, log_message);
         } else {
             self.queue.push(log_message);
         }
@@ -68,35 +106,28 @@ impl LoggerService {
         }
     }
 
-    pub fn log_format_message(
+    pub fn log(
         &mut self,
-        canister: String,
+        level: LogLevel,
         caller: Principal,
-        method: String,
-        kv: Vec<(String, String)>,
+        method: &str,
+        message: String,
+        fields: Vec<(String, LogValue)>,
     ) {
-        let mut message = String::from("");
-        for (k, v) in kv {
-            message.push_str(&format!("{}:{},", k, v));
-        }
-
         self.store_log_message(LogMessageData {
-            timeNanos: api::time(),
-            message: format!(
-                "{}||{}||{}||{}",
-                canister,
-                caller.to_text(),
-                method,
-                message
-            ),
+            timeNanos: ic::time(),
+            message,
+            level,
+            caller: Some(caller),
+            method: method.to_owned(),
+            fields,
         });
     }
 
     pub fn get_log_messages(&self, param: GetLogMessagesParameters) -> CanisterLogMessages {
         let mut data: Vec<LogMessageData> = self
             .queue
-            .clone()
-            .into_iter()
+            .iter()
             .filter(|item| {
                 let mut r = true;
 
@@ -108,6 +139,15 @@ impl LoggerService {
                     if let Some(str) = f.messageContains.clone() {
                         r = r && item.message.contains(&str);
                     }
+                    if let Some(level) = f.minLevel {
+                        r = r && item.level >= level;
+                    }
+                    if let Some(method) = &f.method {
+                        r = r && item.method == *method;
+                    }
+                    if let Some(caller) = f.caller {
+                        r = r && item.caller == Some(caller);
+                    }
                 }
 
                 r
@@ -129,7 +169,7 @@ impl LoggerService {
 
         CanisterLogMessages {
             data,
-            lastAnalyzedMessageTimeNanos: Some(api::time()),
+            lastAnalyzedMessageTimeNanos: Some(ic::time()),
         }
     }
 }
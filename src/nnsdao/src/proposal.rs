@@ -0,0 +1,227 @@
+use crate::bounty::BountyArg;
+use crate::budget::BudgetArg;
+use crate::canister::token_ledger::{TokenConfig, TokenUnits};
+use crate::config::{AdmissionMode, EditPolicy, FeePolicy};
+use crate::dao::DaoInfo;
+use crate::disburse::Amount;
+use crate::sdk::Equities;
+use crate::stream::StreamArg;
+use candid::{CandidType, Principal};
+use ic_ledger_types::Subaccount;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The kinds of proposal the DAO understands
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub enum ProposalKind {
+    Text,
+    TreasuryTransfer,
+    ParameterChange,
+    MembershipChange,
+    CodeUpgrade,
+    DaoInfoUpdate,
+    Bounty,
+    Stream,
+    Budget,
+}
+
+/// Voting rules applied to a proposal kind
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ProposalRule {
+    /// Default and minimum voting period in nanoseconds
+    pub voting_period: u64,
+    /// Minimum total voting weight for the result to count, in whole tokens
+    pub quorum: Equities,
+    /// Percentage of the total voting weight the "yes" side has to exceed
+    pub threshold: u8,
+}
+
+impl ProposalRule {
+    pub fn default_for(kind: ProposalKind) -> Self {
+        let (days, quorum, threshold) = match kind {
+            ProposalKind::Text => (3, 0, 50),
+            ProposalKind::MembershipChange | ProposalKind::DaoInfoUpdate => (3, 0, 50),
+            ProposalKind::TreasuryTransfer
+            | ProposalKind::Bounty
+            | ProposalKind::Stream
+            | ProposalKind::Budget => (7, 10, 60),
+            ProposalKind::ParameterChange | ProposalKind::CodeUpgrade => (7, 10, 66),
+        };
+        ProposalRule {
+            voting_period: days * DAY_NANOS,
+            quorum,
+            threshold,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.voting_period == 0 {
+            return Err(String::from("Voting period must not be zero"));
+        }
+        if self.threshold > 100 {
+            return Err(String::from("Threshold must be a percentage"));
+        }
+        Ok(())
+    }
+
+    /// The rule with its quorum in base units of the ledger the votes are escrowed on
+    pub fn in_units(&self, units: &TokenUnits) -> ProposalRule {
+        let quorum = units
+            .whole(self.quorum)
+            .ok()
+            .and_then(|quorum| Equities::try_from(quorum).ok())
+            .unwrap_or(Equities::MAX);
+        ProposalRule {
+            quorum,
+            ..self.clone()
+        }
+    }
+
+    pub fn reaches_quorum(&self, yes: Equities, no: Equities) -> bool {
+        yes as u128 + no as u128 >= self.quorum as u128
+    }
+
+    /// Whether the votes cast reach the quorum and pass the threshold
+    pub fn is_accepted(&self, yes: Equities, no: Equities) -> bool {
+        let total = yes as u128 + no as u128;
+        self.reaches_quorum(yes, no) && yes as u128 * 100 > total * self.threshold as u128
+    }
+}
+
+/// Pay tokens out of the DAO treasury
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct TreasuryTransfer {
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub amount: Amount,
+}
+
+/// A typed change of the DAO governance rules
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub enum ParameterChange {
+    EditPolicy(EditPolicy),
+    ProposalRule(ProposalKind, ProposalRule),
+    ProposalDeposit(u64),
+    AdmissionMode(AdmissionMode),
+    RegisterToken(TokenConfig),
+    RemoveToken(String),
+    GovernanceToken(String),
+    FeePolicy(FeePolicy),
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum MembershipAction {
+    Add,
+    Remove,
+}
+
+/// Admit or remove a member
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct MembershipChange {
+    pub principal: Principal,
+    pub action: MembershipAction,
+}
+
+/// Upgrade a canister controlled by the DAO
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct CodeUpgrade {
+    pub canister_id: Principal,
+    pub wasm_module: Vec<u8>,
+    pub arg: Vec<u8>,
+}
+
+/// Typed content of a proposal, checked when it is submitted
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub enum ProposalPayload {
+    #[default]
+    Text,
+    TreasuryTransfer(TreasuryTransfer),
+    ParameterChange(ParameterChange),
+    MembershipChange(MembershipChange),
+    CodeUpgrade(CodeUpgrade),
+    DaoInfoUpdate(DaoInfo),
+    /// Fund a bounty from the treasury
+    Bounty(BountyArg),
+    /// Pay an amount over time from the treasury
+    CreateStream(StreamArg),
+    /// Stop the future payments of a stream
+    CancelStream(u64),
+    /// Allocate a budget that its spenders can use without a vote
+    AllocateBudget(BudgetArg),
+}
+
+impl ProposalPayload {
+    pub fn kind(&self) -> ProposalKind {
+        match self {
+            ProposalPayload::Text => ProposalKind::Text,
+            ProposalPayload::TreasuryTransfer(_) => ProposalKind::TreasuryTransfer,
+            ProposalPayload::ParameterChange(_) => ProposalKind::ParameterChange,
+            ProposalPayload::MembershipChange(_) => ProposalKind::MembershipChange,
+            ProposalPayload::CodeUpgrade(_) => ProposalKind::CodeUpgrade,
+            ProposalPayload::DaoInfoUpdate(_) => ProposalKind::DaoInfoUpdate,
+            ProposalPayload::Bounty(_) => ProposalKind::Bounty,
+            ProposalPayload::CreateStream(_) | ProposalPayload::CancelStream(_) => {
+                ProposalKind::Stream
+            }
+            ProposalPayload::AllocateBudget(_) => ProposalKind::Budget,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(quorum: Equities, threshold: u8) -> ProposalRule {
+        ProposalRule {
+            voting_period: 1,
+            quorum,
+            threshold,
+        }
+    }
+
+    #[test]
+    fn quorum_counts_both_sides() {
+        let rule = rule(100, 50);
+        assert!(!rule.reaches_quorum(60, 39));
+        assert!(rule.reaches_quorum(60, 40));
+        assert!(rule.reaches_quorum(0, 100));
+        assert!(rule.reaches_quorum(Equities::MAX, Equities::MAX));
+    }
+
+    #[test]
+    fn yes_has_to_exceed_the_threshold() {
+        let rule = rule(0, 50);
+        assert!(!rule.is_accepted(50, 50));
+        assert!(rule.is_accepted(51, 49));
+        assert!(!rule.is_accepted(0, 0));
+        assert!(rule.is_accepted(Equities::MAX, Equities::MAX - 1));
+
+        let rule = ProposalRule {
+            threshold: 66,
+            ..rule
+        };
+        assert!(!rule.is_accepted(66, 34));
+        assert!(rule.is_accepted(67, 33));
+    }
+
+    #[test]
+    fn accepting_requires_the_quorum() {
+        let rule = rule(100, 50);
+        assert!(!rule.is_accepted(99, 0));
+        assert!(rule.is_accepted(100, 0));
+    }
+
+    #[test]
+    fn quorum_is_converted_from_whole_tokens() {
+        let units = TokenUnits {
+            decimals: 8,
+            fee: 10_000,
+        };
+        let default = ProposalRule::default_for(ProposalKind::TreasuryTransfer);
+        assert_eq!(default.in_units(&units).quorum, 10_0000_0000);
+        assert_eq!(rule(u64::MAX, 50).in_units(&units).quorum, Equities::MAX);
+    }
+}
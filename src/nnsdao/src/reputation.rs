@@ -0,0 +1,284 @@
+//! Reputation of members, awarded from the event log for taking part in governance.
+//!
+//! Points decay exponentially with the half-life set in `ReputationConfig`, a score is
+//! stored with the time it was last decayed and brought up to date when read or awarded.
+//! Activity before the module existed is not counted.
+
+use crate::config::ReputationConfig;
+use crate::events::{Event, EventKind};
+use crate::sdk::{Equities, ProposalState};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+const DAY_NANOS: f64 = 24.0 * 60.0 * 60.0 * 1_000_000_000.0;
+const MAX_LEADERBOARD: usize = 100;
+/// A member's likes award the same author points at most once in this time
+const LIKE_INTERVAL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Clone, Debug)]
+pub struct MemberReputation {
+    pub principal: Principal,
+    pub score: f64,
+    /// Voting weight in percent of the escrowed tokens, 100 unless the multiplier is enabled
+    pub vote_weight_percent: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Default, Debug)]
+struct Score {
+    points: f64,
+    updated_at: u64,
+}
+
+impl Score {
+    fn decayed(&self, now: u64, half_life_days: u64) -> f64 {
+        if half_life_days == 0 || now <= self.updated_at {
+            return self.points;
+        }
+        let days = (now - self.updated_at) as f64 / DAY_NANOS;
+        self.points * 0.5f64.powf(days / half_life_days as f64)
+    }
+}
+
+/// Escrowed tokens scaled by a weight percent taken when the vote was cast
+pub fn weighted(tokens: Equities, percent: u64) -> Equities {
+    (tokens as u128 * percent as u128 / 100).min(Equities::MAX as u128) as Equities
+}
+
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct ReputationService {
+    #[serde(default)]
+    scores: HashMap<Principal, Score>,
+    /// Proposers of the proposals not yet accepted or finalized
+    #[serde(default)]
+    proposers: HashMap<u64, Principal>,
+    /// Members already rewarded for voting on an open proposal
+    #[serde(default)]
+    voters: HashMap<u64, BTreeSet<Principal>>,
+    /// Last time a liker's like awarded points to an author
+    #[serde(default)]
+    likes: HashMap<(Principal, Principal), u64>,
+}
+
+impl ReputationService {
+    pub fn observe(&mut self, event: &Event, config: &ReputationConfig) {
+        let now = event.timestamp;
+        match &event.kind {
+            EventKind::Propose { proposal_id } => {
+                self.proposers.insert(*proposal_id, event.caller);
+                self.voters.insert(*proposal_id, BTreeSet::new());
+                self.award(event.caller, config.propose_points, now, config);
+            }
+            EventKind::Vote { proposal_id, .. } => {
+                let first = self
+                    .voters
+                    .get_mut(proposal_id)
+                    .is_some_and(|voters| voters.insert(event.caller));
+                if first {
+                    self.award(event.caller, config.vote_points, now, config);
+                }
+            }
+            EventKind::WithdrawVote { proposal_id } => {
+                let rewarded = self
+                    .voters
+                    .get_mut(proposal_id)
+                    .is_some_and(|voters| voters.remove(&event.caller));
+                if rewarded {
+                    self.take(event.caller, config.vote_points, now, config);
+                }
+            }
+            EventKind::ProposalState { proposal_id, state } => {
+                self.voters.remove(proposal_id);
+                if *state == ProposalState::Accepted {
+                    if let Some(proposer) = self.proposers.remove(proposal_id) {
+                        self.award(proposer, config.accepted_points, now, config);
+                    }
+                } else if state.is_final() {
+                    self.proposers.remove(proposal_id);
+                }
+            }
+            EventKind::LikeComment { author, .. } => {
+                let last = self.likes.entry((event.caller, *author)).or_insert(0);
+                if *last == 0 || now.saturating_sub(*last) >= LIKE_INTERVAL_NANOS {
+                    *last = now;
+                    self.award(*author, config.like_points, now, config);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn award(&mut self, principal: Principal, points: u64, now: u64, config: &ReputationConfig) {
+        if points == 0 {
+            return;
+        }
+        let score = self.scores.entry(principal).or_default();
+        score.points = score.decayed(now, config.half_life_days) + points as f64;
+        score.updated_at = now;
+    }
+
+    fn take(&mut self, principal: Principal, points: u64, now: u64, config: &ReputationConfig) {
+        if let Some(score) = self.scores.get_mut(&principal) {
+            score.points = (score.decayed(now, config.half_life_days) - points as f64).max(0.0);
+            score.updated_at = now;
+        }
+    }
+
+    pub fn score(&self, principal: Principal, now: u64, config: &ReputationConfig) -> f64 {
+        self.scores
+            .get(&principal)
+            .map(|score| score.decayed(now, config.half_life_days))
+            .unwrap_or_default()
+    }
+
+    /// Percent applied to the escrowed tokens of a vote cast now
+    pub fn vote_weight_percent(
+        &self,
+        principal: Principal,
+        now: u64,
+        config: &ReputationConfig,
+    ) -> u64 {
+        if config.vote_bonus_percent_per_point == 0 {
+            return 100;
+        }
+        let bonus = self.score(principal, now, config) * config.vote_bonus_percent_per_point as f64;
+        100 + (bonus as u64).min(config.max_bonus_percent)
+    }
+
+    pub fn reputation(
+        &self,
+        principal: Principal,
+        now: u64,
+        config: &ReputationConfig,
+    ) -> MemberReputation {
+        MemberReputation {
+            principal,
+            score: self.score(principal, now, config),
+            vote_weight_percent: self.vote_weight_percent(principal, now, config),
+        }
+    }
+
+    /// Members with the highest score first
+    pub fn leaderboard(
+        &self,
+        limit: usize,
+        now: u64,
+        config: &ReputationConfig,
+    ) -> Vec<MemberReputation> {
+        let mut list: Vec<MemberReputation> = self
+            .scores
+            .keys()
+            .map(|principal| self.reputation(*principal, now, config))
+            .collect();
+        list.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(a.principal.cmp(&b.principal))
+        });
+        list.truncate(limit.min(MAX_LEADERBOARD));
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdk::Votes;
+    use crate::test_utils::principal;
+
+    const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    fn assert_close(left: f64, right: f64) {
+        assert!((left - right).abs() < 1e-6, "{} != {}", left, right);
+    }
+
+    fn event(caller: Principal, timestamp: u64, kind: EventKind) -> Event {
+        Event {
+            index: 0,
+            timestamp,
+            caller,
+            kind,
+            parent_hash: None,
+            hash: vec![],
+        }
+    }
+
+    #[test]
+    fn scores_halve_every_half_life() {
+        let config = ReputationConfig::default();
+        let mut service = ReputationService::default();
+        service.award(principal(1), 8, DAY, &config);
+        assert_eq!(service.score(principal(1), DAY, &config), 8.0);
+        assert_eq!(service.score(principal(1), 91 * DAY, &config), 4.0);
+        assert_eq!(service.score(principal(1), 181 * DAY, &config), 2.0);
+        // awarding decays the stored points first
+        service.award(principal(1), 2, 91 * DAY, &config);
+        assert_eq!(service.score(principal(1), 181 * DAY, &config), 3.0);
+
+        let config = ReputationConfig {
+            half_life_days: 0,
+            ..Default::default()
+        };
+        assert_eq!(service.score(principal(1), 1000 * DAY, &config), 6.0);
+    }
+
+    #[test]
+    fn vote_weight_percent_is_capped() {
+        let mut config = ReputationConfig::default();
+        let mut service = ReputationService::default();
+        service.award(principal(1), 3, 0, &config);
+        assert_eq!(service.vote_weight_percent(principal(1), 0, &config), 100);
+
+        config.vote_bonus_percent_per_point = 10;
+        assert_eq!(service.vote_weight_percent(principal(1), 0, &config), 130);
+        service.award(principal(1), 10, 0, &config);
+        assert_eq!(service.vote_weight_percent(principal(1), 0, &config), 150);
+        assert_eq!(service.vote_weight_percent(principal(2), 0, &config), 100);
+        assert_eq!(weighted(10, 150), 15);
+    }
+
+    #[test]
+    fn withdrawn_votes_give_back_their_points() {
+        let config = ReputationConfig::default();
+        let mut service = ReputationService::default();
+        let voter = principal(2);
+        service.observe(
+            &event(principal(1), 0, EventKind::Propose { proposal_id: 1 }),
+            &config,
+        );
+        let vote = EventKind::Vote {
+            proposal_id: 1,
+            vote: Votes::Yes(10),
+        };
+        service.observe(&event(voter, 0, vote.clone()), &config);
+        assert_eq!(service.score(voter, 0, &config), 1.0);
+
+        let withdraw = EventKind::WithdrawVote { proposal_id: 1 };
+        service.observe(&event(voter, 0, withdraw.clone()), &config);
+        assert_eq!(service.score(voter, 0, &config), 0.0);
+        // withdrawing again takes nothing more
+        service.observe(&event(voter, 0, withdraw), &config);
+        service.observe(&event(voter, 0, vote), &config);
+        assert_eq!(service.score(voter, 0, &config), 1.0);
+    }
+
+    #[test]
+    fn likes_of_one_member_count_once_a_day() {
+        let config = ReputationConfig::default();
+        let mut service = ReputationService::default();
+        let author = principal(1);
+        let like = |comment| EventKind::LikeComment {
+            proposal_id: 1,
+            comment,
+            author,
+        };
+        service.observe(&event(principal(2), DAY, like(0)), &config);
+        service.observe(&event(principal(2), DAY + 1, like(1)), &config);
+        assert_close(service.score(author, DAY + 1, &config), 1.0);
+        service.observe(&event(principal(3), DAY + 1, like(1)), &config);
+        assert_close(service.score(author, DAY + 1, &config), 2.0);
+        service.observe(&event(principal(2), 2 * DAY + 1, like(2)), &config);
+        let decayed = 2.0 * 0.5f64.powf(1.0 / 90.0);
+        assert_close(service.score(author, 2 * DAY + 1, &config), decayed + 1.0);
+    }
+}
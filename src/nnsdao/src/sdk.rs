@@ -19,14 +19,18 @@
 
 use std::collections::HashMap;
 
+use crate::proposal::ProposalPayload;
+use crate::stable::{StableMap, ARCHIVED_PROPOSALS, PROPOSALS};
 use async_trait::async_trait;
-use ic_cdk::api;
 use ic_cdk::export::{candid::CandidType, Principal};
+use ic_kit::ic;
 use serde::{Deserialize, Serialize};
 
 /// Voting weight
 pub type Equities = u64;
 
+pub type ProposalMap = StableMap<u64, Proposal, PROPOSALS>;
+
 /// Votes with weights
 #[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub enum Votes {
@@ -34,6 +38,15 @@ pub enum Votes {
     No(Equities),
 }
 
+impl Votes {
+    /// The weight carried by the vote, regardless of its direction
+    pub fn weight(&self) -> Equities {
+        match self {
+            Votes::Yes(weight) | Votes::No(weight) => *weight,
+        }
+    }
+}
+
 /// You need to use the basic methods implemented by the party
 #[async_trait]
 pub trait DaoCustomFn {
@@ -42,6 +55,9 @@ pub trait DaoCustomFn {
 
     /// Implement process completed proposals
     async fn handle_proposal(&self) -> Result<(), String>;
+
+    /// Called with the new content whenever a proposal is created or changed
+    fn proposal_changed(&self, _proposal: &Proposal) {}
 }
 
 /// The state of a Proposal
@@ -64,6 +80,22 @@ pub enum ProposalState {
 
     /// A failure occurred while executing the proposal
     Failed(String),
+
+    /// The proposal was withdrawn by its proposer or removed by a moderator before completion
+    Cancelled,
+}
+
+impl ProposalState {
+    /// Whether the proposal can no longer change
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            ProposalState::Rejected
+                | ProposalState::Succeeded
+                | ProposalState::Failed(_)
+                | ProposalState::Cancelled
+        )
+    }
 }
 
 /// Proposal unit structure
@@ -80,6 +112,31 @@ pub struct Proposal {
     pub end_time: u64,
     pub timestamp: u64,
     pub comment: Vec<Comment>,
+    #[serde(default)]
+    pub vote_history: Vec<VoteRecord>,
+    #[serde(default)]
+    pub revisions: Vec<ProposalRevision>,
+    #[serde(default)]
+    pub payload: ProposalPayload,
+    /// Ledger block of the transfer made when executing the proposal
+    #[serde(default)]
+    pub block_index: Option<u64>,
+}
+
+/// A version of the proposal text, the first revision is the original submission
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ProposalRevision {
+    pub title: String,
+    pub content: String,
+    pub timestamp: u64,
+}
+
+/// A single entry in the voting history of a proposal, `None` means the vote was withdrawn
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct VoteRecord {
+    pub principal: Principal,
+    pub vote: Option<Votes>,
+    pub timestamp: u64,
 }
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Comment {
@@ -98,6 +155,15 @@ pub struct ProposalArg {
     pub property: Option<HashMap<String, String>>,
     pub start_time: u64,
     pub end_time: u64,
+    pub payload: ProposalPayload,
+}
+
+/// Edit parameters for the proposal
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EditProposalArg {
+    pub id: u64,
+    pub title: String,
+    pub content: String,
 }
 
 /// Voting parameters
@@ -116,9 +182,14 @@ pub struct ChangeProposalStateArg {
 }
 
 /// Basic DAO structure
-#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct DaoBasic<T: DaoCustomFn> {
-    pub proposal_list: HashMap<u64, Proposal>,
+    /// Kept in stable memory, see `stable`
+    #[serde(skip)]
+    pub proposal_list: ProposalMap,
+    /// Finalized proposals moved out of `proposal_list`, read only
+    #[serde(skip)]
+    pub archived_proposals: StableMap<u64, Proposal, ARCHIVED_PROPOSALS>,
     pub next_proposal_id: u64,
     pub custom_fn: T,
 }
@@ -131,18 +202,61 @@ where
     /// Instantiate the underlying DAO
     pub fn new(custom_fn: T) -> Self {
         DaoBasic {
-            proposal_list: HashMap::default(),
+            proposal_list: Default::default(),
+            archived_proposals: Default::default(),
             next_proposal_id: 1,
             custom_fn,
         }
     }
-    pub fn comment(&mut self, id: u64, comment: Comment) -> Result<Proposal, String> {
-        let proposal = self
+    /// Change a stored proposal, nothing is written when `f` fails
+    pub fn with_proposal<R>(
+        &mut self,
+        id: u64,
+        f: impl FnOnce(&mut Proposal) -> Result<R, String>,
+    ) -> Result<R, String> {
+        let (result, proposal) = self
             .proposal_list
-            .get_mut(&id)
+            .update(id, |proposal| Ok((f(proposal)?, proposal.clone())))?
             .ok_or_else(|| "no proposal".to_owned())?;
-        proposal.comment.push(comment);
-        Ok(proposal.clone())
+        self.custom_fn.proposal_changed(&proposal);
+        Ok(result)
+    }
+    /// Add a comment of `caller`, the author and likes given by the client are ignored
+    pub fn comment(
+        &mut self,
+        id: u64,
+        caller: Principal,
+        mut comment: Comment,
+    ) -> Result<Proposal, String> {
+        comment.principal = caller;
+        comment.update_at = ic::time();
+        comment.like.clear();
+        self.with_proposal(id, |proposal| {
+            proposal.comment.push(comment);
+            Ok(proposal.clone())
+        })
+    }
+    /// Like a comment, returns its author
+    pub fn like_comment(
+        &mut self,
+        id: u64,
+        index: u64,
+        caller: Principal,
+    ) -> Result<Principal, String> {
+        self.with_proposal(id, |proposal| {
+            let comment = proposal
+                .comment
+                .get_mut(index as usize)
+                .ok_or_else(|| String::from("Comment does not exist"))?;
+            if comment.principal == caller {
+                return Err(String::from("Comments can not be liked by their author"));
+            }
+            if comment.like.contains(&caller) {
+                return Err(String::from("Comment is already liked"));
+            }
+            comment.like.push(caller);
+            Ok(comment.principal)
+        })
     }
     /// Submit the proposal
     pub async fn proposal(&mut self, arg: ProposalArg) -> Result<Proposal, String> {
@@ -157,11 +271,16 @@ where
             property: arg.property,
             start_time: arg.start_time,
             end_time: arg.end_time,
-            timestamp: api::time(),
+            timestamp: ic::time(),
             comment: Default::default(),
+            vote_history: Default::default(),
+            revisions: Default::default(),
+            payload: arg.payload,
+            block_index: None,
         };
         self.proposal_list
             .insert(self.next_proposal_id, proposal.clone());
+        self.custom_fn.proposal_changed(&proposal);
         self.next_proposal_id += 1;
         Ok(proposal)
     }
@@ -169,27 +288,114 @@ where
     pub fn get_proposal(&self, id: u64) -> Result<Proposal, String> {
         self.proposal_list
             .get(&id)
+            .or_else(|| self.archived_proposals.get(&id))
             .ok_or_else(|| "no proposal".to_owned())
-            .cloned()
     }
 
     pub fn proposal_list(&self) -> HashMap<u64, Proposal> {
-        self.proposal_list.clone()
+        self.proposal_list.iter().collect()
     }
 
-    pub async fn vote(&mut self, arg: VotesArg) -> Result<(), String> {
-        self.custom_fn.is_member(arg.caller.clone()).await?;
-        if let Some(proposal) = self.proposal_list.get_mut(&arg.id) {
-            for data in proposal.vote_data.iter() {
-                if data.0 == arg.caller {
-                    return Err(String::from("Users have voted"));
-                }
+    /// Proposals ordered by id, optionally with the archived ones
+    pub fn proposals(&self, include_archived: bool) -> Vec<Proposal> {
+        let mut list: Vec<Proposal> = self.proposal_list.values().collect();
+        if include_archived {
+            list.extend(self.archived_proposals.values());
+        }
+        list.sort_by_key(|proposal| proposal.id);
+        list
+    }
+
+    /// One page of the proposals newest first and the number of proposals, only the
+    /// proposals of the page are read
+    pub fn proposals_page(
+        &self,
+        include_archived: bool,
+        offset: usize,
+        limit: usize,
+    ) -> (u64, Vec<Proposal>) {
+        let mut total = self.proposal_list.len();
+        if include_archived {
+            total += self.archived_proposals.len();
+        }
+        let items = (0..self.next_proposal_id)
+            .rev()
+            .filter(|id| {
+                self.proposal_list.contains_key(id)
+                    || (include_archived && self.archived_proposals.contains_key(id))
+            })
+            .skip(offset)
+            .take(limit)
+            .filter_map(|id| self.get_proposal(id).ok())
+            .collect();
+        (total, items)
+    }
+
+    /// Move at most `limit` finalized proposals that ended before `before` to the archive
+    pub fn archive_proposals(&mut self, before: u64, limit: usize) -> Vec<u64> {
+        let ids: Vec<u64> = self
+            .proposal_list
+            .iter()
+            .filter(|(_, proposal)| {
+                proposal.proposal_state.is_final() && proposal.end_time < before
+            })
+            .map(|(id, _)| id)
+            .take(limit)
+            .collect();
+        for id in &ids {
+            if let Some(proposal) = self.proposal_list.remove(id) {
+                self.archived_proposals.insert(*id, proposal);
             }
-            proposal.vote_data.push((arg.caller.clone(), arg.vote))
-        } else {
+        }
+        ids
+    }
+
+    /// Cast a vote, or replace the caller's previous vote, returning the replaced vote
+    pub async fn vote(&mut self, arg: VotesArg) -> Result<Option<Votes>, String> {
+        self.custom_fn.is_member(arg.caller).await?;
+        self.set_vote(arg.id, arg.caller, Some(arg.vote))
+    }
+
+    /// Retract the caller's vote, returning the withdrawn vote
+    pub fn withdraw_vote(&mut self, id: u64, caller: Principal) -> Result<Votes, String> {
+        self.set_vote(id, caller, None)?
+            .ok_or_else(|| String::from("Users have not voted"))
+    }
+
+    /// Set the caller's vote on an open proposal, `None` removes it.
+    /// Returns the vote that was replaced, no membership check is done
+    pub fn set_vote(
+        &mut self,
+        id: u64,
+        caller: Principal,
+        vote: Option<Votes>,
+    ) -> Result<Option<Votes>, String> {
+        if !self.proposal_list.contains_key(&id) {
             return Err(String::from("The proposal does not exist"));
         }
-        Ok(())
+        self.with_proposal(id, |proposal| {
+            if proposal.proposal_state != ProposalState::Open {
+                return Err(String::from("Proposal is not open for voting"));
+            }
+            let index = proposal.vote_data.iter().position(|data| data.0 == caller);
+            let previous = match (index, vote.clone()) {
+                (Some(index), Some(vote)) => {
+                    Some(std::mem::replace(&mut proposal.vote_data[index].1, vote))
+                }
+                (Some(index), None) => Some(proposal.vote_data.remove(index).1),
+                (None, Some(vote)) => {
+                    proposal.vote_data.push((caller, vote));
+                    None
+                }
+                (None, None) => return Err(String::from("Users have not voted")),
+            };
+            proposal.vote_history.push(VoteRecord {
+                principal: caller,
+                vote,
+                timestamp: ic::time(),
+            });
+            Ok(previous)
+        })
     }
 
     pub async fn handle_proposal(&self) -> Result<(), String> {
@@ -197,9 +403,62 @@ where
         Ok(())
     }
 
+    /// Replace the title and content of an open proposal, keeping every version as a revision
+    pub fn edit_proposal(&mut self, arg: EditProposalArg) -> Result<Proposal, String> {
+        self.with_proposal(arg.id, |proposal| {
+            if proposal.proposal_state != ProposalState::Open {
+                return Err(String::from("Only open proposals can be edited"));
+            }
+            if proposal.revisions.is_empty() {
+                proposal.revisions.push(ProposalRevision {
+                    title: proposal.title.clone(),
+                    content: proposal.content.clone(),
+                    timestamp: proposal.timestamp,
+                });
+            }
+            proposal.revisions.push(ProposalRevision {
+                title: arg.title.clone(),
+                content: arg.content.clone(),
+                timestamp: ic::time(),
+            });
+            proposal.title = arg.title;
+            proposal.content = arg.content;
+            Ok(proposal.clone())
+        })
+    }
+
+    /// Drop every vote on an open proposal, returning the removed votes
+    pub fn reset_votes(&mut self, id: u64) -> Result<Vec<(Principal, Votes)>, String> {
+        self.with_proposal(id, |proposal| {
+            if proposal.proposal_state != ProposalState::Open {
+                return Err(String::from("Only votes of open proposals can be reset"));
+            }
+            Ok(take_votes(proposal))
+        })
+    }
+
+    /// Cancel an open proposal before its voting period is over, returning it with the
+    /// votes that were on it
+    pub fn cancel_proposal(
+        &mut self,
+        id: u64,
+    ) -> Result<(Proposal, Vec<(Principal, Votes)>), String> {
+        self.with_proposal(id, |proposal| {
+            if proposal.proposal_state != ProposalState::Open {
+                return Err(String::from("Only open proposals can be cancelled"));
+            }
+            if ic::time() > proposal.end_time {
+                return Err(String::from("The voting period of the proposal is over"));
+            }
+            let votes = take_votes(proposal);
+            proposal.proposal_state = ProposalState::Cancelled;
+            Ok((proposal.clone(), votes))
+        })
+    }
+
     pub fn change_proposal_state(&mut self, arg: ChangeProposalStateArg) -> Result<(), String> {
-        if let Some(proposal) = self.proposal_list.get_mut(&arg.id) {
-            if proposal.end_time >= api::time() {
+        self.with_proposal(arg.id, |proposal| {
+            if proposal.end_time >= ic::time() {
                 return Err(String::from("Proposal time is not over"));
             }
             match proposal.proposal_state {
@@ -231,11 +490,22 @@ where
                     ))
                 }
             }
-        } else {
-            return Err(String::from("no proposal"));
-        }
-        Ok(())
+            Ok(())
+        })
+    }
+}
+
+/// Remove the votes of a proposal, recording the withdrawals in its history
+fn take_votes(proposal: &mut Proposal) -> Vec<(Principal, Votes)> {
+    let votes = std::mem::take(&mut proposal.vote_data);
+    for vote in &votes {
+        proposal.vote_history.push(VoteRecord {
+            principal: vote.0,
+            vote: None,
+            timestamp: ic::time(),
+        });
     }
+    votes
 }
 
 // #[cfg(test)]
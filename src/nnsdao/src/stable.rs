@@ -0,0 +1,827 @@
+//! Collections kept in stable memory, so they neither count against the wasm heap nor have
+//! to be serialized on upgrade.
+//!
+//! The first page holds the superblock, the rest is handed out by a bump allocator with a
+//! free list per power-of-two size class:
+//!
+//! ```text
+//! 0                      magic "NDSM"
+//! 4                      layout version: u32
+//! 8                      end of allocated memory: u64
+//! 16                     free list heads: u64 per size class
+//! 16 + 8 * SIZE_CLASSES  collection headers: 48 bytes per collection id
+//! ```
+//!
+//! Values are stored as cbor blobs. A map is an open addressing hash table of blob
+//! references, a vec is an array of blob references.
+
+#[cfg(test)]
+use self::test_memory::{stable64_grow, stable64_read, stable64_size, stable64_write};
+#[cfg(not(test))]
+use ic_cdk::api::stable::{stable64_grow, stable64_read, stable64_size, stable64_write};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use std::marker::PhantomData;
+
+const MAGIC: &[u8; 4] = b"NDSM";
+const LAYOUT_VERSION: u32 = 1;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const SUPERBLOCK_SIZE: u64 = WASM_PAGE_SIZE;
+const SIZE_CLASSES: u64 = 40;
+const MIN_BLOCK_SHIFT: u64 = 6;
+const FREE_LISTS: u64 = 16;
+const HEADERS: u64 = FREE_LISTS + 8 * SIZE_CLASSES;
+const HEADER_SIZE: u64 = 48;
+const MAX_COLLECTIONS: u8 = 32;
+
+/// Collection ids, each owns one header in the superblock
+pub const MEMBERS: u8 = 0;
+pub const PROPOSALS: u8 = 1;
+pub const LOGS: u8 = 2;
+pub const ARCHIVED_PROPOSALS: u8 = 3;
+pub const EVENTS: u8 = 4;
+/// Blob holding the state written by `pre_upgrade`
+pub const UPGRADE_STATE: u8 = MAX_COLLECTIONS - 1;
+
+fn read_u64(offset: u64) -> u64 {
+    let mut buf = [0u8; 8];
+    stable64_read(offset, &mut buf);
+    u64::from_le_bytes(buf)
+}
+
+fn write_u64(offset: u64, value: u64) {
+    stable64_write(offset, &value.to_le_bytes());
+}
+
+fn read_bytes(offset: u64, len: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; len as usize];
+    stable64_read(offset, &mut buf);
+    buf
+}
+
+fn ensure_capacity(end: u64) {
+    let pages = stable64_size();
+    if end > pages * WASM_PAGE_SIZE {
+        let needed = end.div_ceil(WASM_PAGE_SIZE) - pages;
+        if stable64_grow(needed).is_err() {
+            ic_cdk::trap("Out of stable memory");
+        }
+    }
+}
+
+/// Whether the stable memory holds a formatted superblock
+pub fn is_initialized() -> bool {
+    if stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 4];
+    stable64_read(0, &mut magic);
+    &magic == MAGIC
+}
+
+/// Format the stable memory, dropping everything it held
+pub fn initialize() {
+    ensure_capacity(SUPERBLOCK_SIZE);
+    stable64_write(0, &vec![0u8; SUPERBLOCK_SIZE as usize]);
+    stable64_write(0, MAGIC);
+    stable64_write(4, &LAYOUT_VERSION.to_le_bytes());
+    write_u64(8, SUPERBLOCK_SIZE);
+}
+
+/// A block of stable memory handed out by the allocator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Block {
+    offset: u64,
+    class: u8,
+}
+
+fn allocate(len: u64) -> Block {
+    let mut class = 0;
+    while (1u64 << (class + MIN_BLOCK_SHIFT)) < len {
+        class += 1;
+    }
+    if class >= SIZE_CLASSES {
+        ic_cdk::trap("Stable memory allocation is too large");
+    }
+    let head = FREE_LISTS + 8 * class;
+    let free = read_u64(head);
+    if free != 0 {
+        write_u64(head, read_u64(free));
+        return Block {
+            offset: free,
+            class: class as u8,
+        };
+    }
+    let offset = read_u64(8);
+    let end = offset + (1 << (class + MIN_BLOCK_SHIFT));
+    ensure_capacity(end);
+    write_u64(8, end);
+    Block {
+        offset,
+        class: class as u8,
+    }
+}
+
+fn deallocate(block: Block) {
+    let head = FREE_LISTS + 8 * block.class as u64;
+    write_u64(block.offset, read_u64(head));
+    write_u64(head, block.offset);
+}
+
+/// Reference to a blob: offset u64, length u32, size class u8
+const BLOB_REF_SIZE: u64 = 16;
+
+#[derive(Clone, Copy, Debug)]
+struct BlobRef {
+    block: Block,
+    len: u32,
+}
+
+impl BlobRef {
+    fn store(bytes: &[u8]) -> Self {
+        let block = allocate(bytes.len() as u64);
+        stable64_write(block.offset, bytes);
+        BlobRef {
+            block,
+            len: bytes.len() as u32,
+        }
+    }
+
+    fn load(&self) -> Vec<u8> {
+        read_bytes(self.block.offset, self.len as u64)
+    }
+
+    fn read(offset: u64) -> Option<Self> {
+        let buf = read_bytes(offset, BLOB_REF_SIZE);
+        let mut block_offset = [0u8; 8];
+        block_offset.copy_from_slice(&buf[..8]);
+        let block_offset = u64::from_le_bytes(block_offset);
+        if block_offset == 0 {
+            return None;
+        }
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&buf[8..12]);
+        Some(BlobRef {
+            block: Block {
+                offset: block_offset,
+                class: buf[12],
+            },
+            len: u32::from_le_bytes(len),
+        })
+    }
+
+    fn write(offset: u64, blob: Option<Self>) {
+        let mut buf = [0u8; BLOB_REF_SIZE as usize];
+        if let Some(blob) = blob {
+            buf[..8].copy_from_slice(&blob.block.offset.to_le_bytes());
+            buf[8..12].copy_from_slice(&blob.len.to_le_bytes());
+            buf[12] = blob.block.class;
+        }
+        stable64_write(offset, &buf);
+    }
+}
+
+fn header(id: u8) -> u64 {
+    if id >= MAX_COLLECTIONS {
+        ic_cdk::trap("Unknown stable collection");
+    }
+    HEADERS + HEADER_SIZE * id as u64
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_cbor::to_vec(value).expect("Failed to serialize stable value.")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> T {
+    serde_cbor::from_slice(bytes).expect("Failed to deserialize stable value.")
+}
+
+/// A single value kept in stable memory
+pub fn write_blob(id: u8, bytes: &[u8]) {
+    let offset = header(id);
+    if let Some(old) = BlobRef::read(offset) {
+        deallocate(old.block);
+    }
+    BlobRef::write(offset, Some(BlobRef::store(bytes)));
+}
+
+pub fn read_blob(id: u8) -> Option<Vec<u8>> {
+    BlobRef::read(header(id)).map(|blob| blob.load())
+}
+
+/// Bucket: state u8, class u8, key length u16, blob length u32, hash u64, offset u64
+const BUCKET_SIZE: u64 = 24;
+const EMPTY: u8 = 0;
+const USED: u8 = 1;
+const REMOVED: u8 = 2;
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    state: u8,
+    key_len: u16,
+    hash: u64,
+    blob: BlobRef,
+}
+
+impl Bucket {
+    fn read(offset: u64) -> Self {
+        let buf = read_bytes(offset, BUCKET_SIZE);
+        let mut key_len = [0u8; 2];
+        key_len.copy_from_slice(&buf[2..4]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&buf[4..8]);
+        let mut hash = [0u8; 8];
+        hash.copy_from_slice(&buf[8..16]);
+        let mut block_offset = [0u8; 8];
+        block_offset.copy_from_slice(&buf[16..24]);
+        Bucket {
+            state: buf[0],
+            key_len: u16::from_le_bytes(key_len),
+            hash: u64::from_le_bytes(hash),
+            blob: BlobRef {
+                block: Block {
+                    offset: u64::from_le_bytes(block_offset),
+                    class: buf[1],
+                },
+                len: u32::from_le_bytes(len),
+            },
+        }
+    }
+
+    fn write(&self, offset: u64) {
+        let mut buf = [0u8; BUCKET_SIZE as usize];
+        buf[0] = self.state;
+        buf[1] = self.blob.block.class;
+        buf[2..4].copy_from_slice(&self.key_len.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.blob.len.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.hash.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.blob.block.offset.to_le_bytes());
+        stable64_write(offset, &buf);
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Header of a collection: table block u64, table class u64, capacity u64, length u64,
+/// removed map slots u64
+#[derive(Clone, Copy, Debug, Default)]
+struct TableHeader {
+    table: u64,
+    class: u64,
+    capacity: u64,
+    len: u64,
+    removed: u64,
+}
+
+fn read_header(id: u8) -> TableHeader {
+    let offset = header(id);
+    TableHeader {
+        table: read_u64(offset),
+        class: read_u64(offset + 8),
+        capacity: read_u64(offset + 16),
+        len: read_u64(offset + 24),
+        removed: read_u64(offset + 32),
+    }
+}
+
+fn write_header(id: u8, header: TableHeader) {
+    let offset = self::header(id);
+    write_u64(offset, header.table);
+    write_u64(offset + 8, header.class);
+    write_u64(offset + 16, header.capacity);
+    write_u64(offset + 24, header.len);
+    write_u64(offset + 32, header.removed);
+}
+
+/// A hash map whose entries live in stable memory, `ID` selects its header. A value of this
+/// type is a handle to the storage, it is not `Clone` as a copy would share the entries.
+pub struct StableMap<K, V, const ID: u8> {
+    _marker: PhantomData<(K, V)>,
+}
+
+impl<K, V, const ID: u8> Default for StableMap<K, V, ID> {
+    fn default() -> Self {
+        StableMap {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, const ID: u8> fmt::Debug for StableMap<K, V, ID> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StableMap({})", ID)
+    }
+}
+
+impl<K, V, const ID: u8> StableMap<K, V, ID>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn header(&self) -> TableHeader {
+        read_header(ID)
+    }
+
+    fn set_header(&self, header: TableHeader) {
+        write_header(ID, header)
+    }
+
+    fn bucket_offset(header: &TableHeader, slot: u64) -> u64 {
+        header.table + slot * BUCKET_SIZE
+    }
+
+    /// Slot holding the key, or the slot it would be inserted at
+    fn probe(header: &TableHeader, key: &[u8], hash: u64) -> (u64, bool) {
+        let mut insert_at = None;
+        let mut slot = hash % header.capacity;
+        for _ in 0..header.capacity {
+            let bucket = Bucket::read(Self::bucket_offset(header, slot));
+            match bucket.state {
+                EMPTY => return (insert_at.unwrap_or(slot), false),
+                REMOVED => {
+                    insert_at.get_or_insert(slot);
+                }
+                _ => {
+                    if bucket.hash == hash
+                        && bucket.key_len as usize == key.len()
+                        && read_bytes(bucket.blob.block.offset, key.len() as u64) == key
+                    {
+                        return (slot, true);
+                    }
+                }
+            }
+            slot = (slot + 1) % header.capacity;
+        }
+        match insert_at {
+            Some(slot) => (slot, false),
+            None => ic_cdk::trap("Stable map is full"),
+        }
+    }
+
+    fn resize(&self, mut header: TableHeader, capacity: u64) -> TableHeader {
+        let old = header;
+        let block = allocate(capacity * BUCKET_SIZE);
+        stable64_write(block.offset, &vec![0u8; (capacity * BUCKET_SIZE) as usize]);
+        header = TableHeader {
+            table: block.offset,
+            class: block.class as u64,
+            capacity,
+            len: old.len,
+            removed: 0,
+        };
+        for slot in 0..old.capacity {
+            let bucket = Bucket::read(Self::bucket_offset(&old, slot));
+            if bucket.state != USED {
+                continue;
+            }
+            let mut target = bucket.hash % capacity;
+            while Bucket::read(Self::bucket_offset(&header, target)).state != EMPTY {
+                target = (target + 1) % capacity;
+            }
+            bucket.write(Self::bucket_offset(&header, target));
+        }
+        if old.capacity > 0 {
+            deallocate(Block {
+                offset: old.table,
+                class: old.class as u8,
+            });
+        }
+        self.set_header(header);
+        header
+    }
+
+    pub fn len(&self) -> u64 {
+        self.header().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let header = self.header();
+        if header.capacity == 0 {
+            return false;
+        }
+        let key = encode(key);
+        Self::probe(&header, &key, fnv1a(&key)).1
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let header = self.header();
+        if header.capacity == 0 {
+            return None;
+        }
+        let key = encode(key);
+        let (slot, found) = Self::probe(&header, &key, fnv1a(&key));
+        if !found {
+            return None;
+        }
+        let bucket = Bucket::read(Self::bucket_offset(&header, slot));
+        Some(decode(&bucket.blob.load()[key.len()..]))
+    }
+
+    /// Insert a value, returning the one it replaced
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut header = self.header();
+        // keep the load, removed slots included, under 70%
+        if (header.len + header.removed + 1) * 10 > header.capacity * 7 {
+            let capacity = if (header.len + 1) * 2 > header.capacity {
+                (header.capacity * 2).max(16)
+            } else {
+                header.capacity
+            };
+            header = self.resize(header, capacity);
+        }
+        let key = encode(&key);
+        let hash = fnv1a(&key);
+        let (slot, found) = Self::probe(&header, &key, hash);
+        let offset = Self::bucket_offset(&header, slot);
+        let bucket = Bucket::read(offset);
+        let previous = if found {
+            let bytes = bucket.blob.load();
+            deallocate(bucket.blob.block);
+            Some(decode(&bytes[key.len()..]))
+        } else {
+            if bucket.state == REMOVED {
+                header.removed -= 1;
+            }
+            header.len += 1;
+            self.set_header(header);
+            None
+        };
+        let mut bytes = key.clone();
+        bytes.extend_from_slice(&encode(&value));
+        Bucket {
+            state: USED,
+            key_len: key.len() as u16,
+            hash,
+            blob: BlobRef::store(&bytes),
+        }
+        .write(offset);
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let mut header = self.header();
+        if header.capacity == 0 {
+            return None;
+        }
+        let key = encode(key);
+        let (slot, found) = Self::probe(&header, &key, fnv1a(&key));
+        if !found {
+            return None;
+        }
+        let offset = Self::bucket_offset(&header, slot);
+        let mut bucket = Bucket::read(offset);
+        let bytes = bucket.blob.load();
+        deallocate(bucket.blob.block);
+        bucket.state = REMOVED;
+        bucket.write(offset);
+        header.len -= 1;
+        header.removed += 1;
+        self.set_header(header);
+        Some(decode(&bytes[key.len()..]))
+    }
+
+    /// Read, change and write back a value, nothing is written when `f` fails
+    pub fn update<R>(
+        &mut self,
+        key: K,
+        f: impl FnOnce(&mut V) -> Result<R, String>,
+    ) -> Result<Option<R>, String> {
+        let mut value = match self.get(&key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let result = f(&mut value)?;
+        self.insert(key, value);
+        Ok(Some(result))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> {
+        let header = self.header();
+        (0..header.capacity).filter_map(move |slot| {
+            let bucket = Bucket::read(Self::bucket_offset(&header, slot));
+            if bucket.state != USED {
+                return None;
+            }
+            let bytes = bucket.blob.load();
+            let (key, value) = bytes.split_at(bucket.key_len as usize);
+            Some((decode(key), decode(value)))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Values from the `offset`th entry on in storage order, entries before the page are
+    /// skipped without being read
+    pub fn page(&self, offset: usize, limit: usize) -> Vec<V> {
+        let header = self.header();
+        (0..header.capacity)
+            .map(|slot| Bucket::read(Self::bucket_offset(&header, slot)))
+            .filter(|bucket| bucket.state == USED)
+            .skip(offset)
+            .take(limit)
+            .map(|bucket| decode(&bucket.blob.load()[bucket.key_len as usize..]))
+            .collect()
+    }
+}
+
+/// A vec whose values live in stable memory, `ID` selects its header, see `StableMap`
+pub struct StableVec<T, const ID: u8> {
+    _marker: PhantomData<T>,
+}
+
+impl<T, const ID: u8> Default for StableVec<T, ID> {
+    fn default() -> Self {
+        StableVec {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, const ID: u8> fmt::Debug for StableVec<T, ID> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StableVec({})", ID)
+    }
+}
+
+impl<T, const ID: u8> StableVec<T, ID>
+where
+    T: Serialize + DeserializeOwned,
+{
+    fn header(&self) -> TableHeader {
+        read_header(ID)
+    }
+
+    fn set_header(&self, header: TableHeader) {
+        write_header(ID, header)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.header().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: u64) -> Option<T> {
+        let header = self.header();
+        if index >= header.len {
+            return None;
+        }
+        BlobRef::read(header.table + index * BLOB_REF_SIZE).map(|blob| decode(&blob.load()))
+    }
+
+    pub fn push(&mut self, value: T) {
+        let mut header = self.header();
+        if header.len == header.capacity {
+            let capacity = (header.capacity * 2).max(64);
+            let block = allocate(capacity * BLOB_REF_SIZE);
+            let mut index = read_bytes(header.table, header.capacity * BLOB_REF_SIZE);
+            index.resize((capacity * BLOB_REF_SIZE) as usize, 0);
+            stable64_write(block.offset, &index);
+            if header.capacity > 0 {
+                deallocate(Block {
+                    offset: header.table,
+                    class: header.class as u8,
+                });
+            }
+            header.table = block.offset;
+            header.class = block.class as u64;
+            header.capacity = capacity;
+        }
+        BlobRef::write(
+            header.table + header.len * BLOB_REF_SIZE,
+            Some(BlobRef::store(&encode(&value))),
+        );
+        header.len += 1;
+        self.set_header(header);
+    }
+
+    /// Replace the value at `index`, which must be below `len`
+    pub fn set(&mut self, index: u64, value: T) {
+        let header = self.header();
+        if index >= header.len {
+            ic_cdk::trap("Stable vec index out of bounds");
+        }
+        let offset = header.table + index * BLOB_REF_SIZE;
+        if let Some(old) = BlobRef::read(offset) {
+            deallocate(old.block);
+        }
+        BlobRef::write(offset, Some(BlobRef::store(&encode(&value))));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = T> {
+        let header = self.header();
+        (0..header.len).filter_map(move |index| {
+            BlobRef::read(header.table + index * BLOB_REF_SIZE).map(|blob| decode(&blob.load()))
+        })
+    }
+}
+
+/// Stable memory of the test thread, kept on the heap
+#[cfg(test)]
+mod test_memory {
+    use super::WASM_PAGE_SIZE;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static MEMORY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn stable64_size() -> u64 {
+        MEMORY.with(|memory| memory.borrow().len() as u64 / WASM_PAGE_SIZE)
+    }
+
+    pub fn stable64_grow(pages: u64) -> Result<u64, ()> {
+        MEMORY.with(|memory| {
+            let mut memory = memory.borrow_mut();
+            let previous = memory.len() as u64 / WASM_PAGE_SIZE;
+            memory.resize(((previous + pages) * WASM_PAGE_SIZE) as usize, 0);
+            Ok(previous)
+        })
+    }
+
+    pub fn stable64_read(offset: u64, buf: &mut [u8]) {
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            buf.copy_from_slice(&memory.borrow()[offset..offset + buf.len()]);
+        })
+    }
+
+    pub fn stable64_write(offset: u64, buf: &[u8]) {
+        MEMORY.with(|memory| {
+            let offset = offset as usize;
+            memory.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const TEST_MAP: u8 = 10;
+    const TEST_VEC: u8 = 11;
+
+    fn allocated() -> u64 {
+        read_u64(8)
+    }
+
+    #[test]
+    fn map_insert_get_and_replace() {
+        initialize();
+        let mut map: StableMap<u64, String, TEST_MAP> = StableMap::default();
+        assert!(map.is_empty());
+        assert_eq!(map.insert(1, String::from("one")), None);
+        assert_eq!(map.insert(2, String::from("two")), None);
+        assert_eq!(
+            map.insert(1, String::from("uno")),
+            Some(String::from("one"))
+        );
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&1), Some(String::from("uno")));
+        assert_eq!(map.get(&3), None);
+        assert!(map.contains_key(&2));
+    }
+
+    #[test]
+    fn map_remove_leaves_other_keys_reachable() {
+        initialize();
+        let mut map: StableMap<u64, u64, TEST_MAP> = StableMap::default();
+        for key in 0..10 {
+            map.insert(key, key * 10);
+        }
+        assert_eq!(map.remove(&4), Some(40));
+        assert_eq!(map.remove(&4), None);
+        assert_eq!(map.len(), 9);
+        for key in (0..10).filter(|key| *key != 4) {
+            assert_eq!(map.get(&key), Some(key * 10));
+        }
+        // the removed slot is reused
+        map.insert(4, 41);
+        assert_eq!(map.get(&4), Some(41));
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn map_resize_keeps_every_entry() {
+        initialize();
+        let mut map: StableMap<u64, u64, TEST_MAP> = StableMap::default();
+        let mut expected = HashMap::new();
+        for key in 0..500 {
+            map.insert(key, key + 1);
+            expected.insert(key, key + 1);
+            if key % 3 == 0 {
+                map.remove(&(key / 2));
+                expected.remove(&(key / 2));
+            }
+        }
+        assert_eq!(map.len(), expected.len() as u64);
+        let entries: HashMap<u64, u64> = map.iter().collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn map_pages_follow_the_storage_order() {
+        initialize();
+        let mut map: StableMap<u64, u64, TEST_MAP> = StableMap::default();
+        for key in 0..50 {
+            map.insert(key, key);
+        }
+        let values: Vec<u64> = map.values().collect();
+        assert_eq!(map.page(0, 20), values[..20]);
+        assert_eq!(map.page(45, 20), values[45..]);
+        assert!(map.page(50, 20).is_empty());
+    }
+
+    #[test]
+    fn map_update_writes_only_on_success() {
+        initialize();
+        let mut map: StableMap<u64, u64, TEST_MAP> = StableMap::default();
+        map.insert(1, 1);
+        assert_eq!(
+            map.update(1, |value| {
+                *value += 1;
+                Ok(*value)
+            }),
+            Ok(Some(2))
+        );
+        assert_eq!(
+            map.update(1, |value| {
+                *value += 1;
+                Err::<(), _>(String::from("no"))
+            }),
+            Err(String::from("no"))
+        );
+        assert_eq!(map.get(&1), Some(2));
+        assert_eq!(map.update(2, |_| Ok(())), Ok(None));
+    }
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        initialize();
+        let mut map: StableMap<u64, Vec<u8>, TEST_MAP> = StableMap::default();
+        map.insert(1, vec![1; 100]);
+        let end = allocated();
+        for _ in 0..10 {
+            map.insert(1, vec![2; 100]);
+        }
+        assert_eq!(allocated(), end);
+    }
+
+    #[test]
+    fn collections_are_found_again_by_id() {
+        initialize();
+        {
+            let mut map: StableMap<String, u64, TEST_MAP> = StableMap::default();
+            let mut vec: StableVec<String, TEST_VEC> = StableVec::default();
+            map.insert(String::from("a"), 1);
+            vec.push(String::from("x"));
+        }
+
+        // a new handle, as after an upgrade, reads the same storage
+        assert!(is_initialized());
+        let map: StableMap<String, u64, TEST_MAP> = StableMap::default();
+        let vec: StableVec<String, TEST_VEC> = StableVec::default();
+        assert_eq!(map.get(&String::from("a")), Some(1));
+        assert_eq!(vec.get(0), Some(String::from("x")));
+    }
+
+    #[test]
+    fn vec_push_get_set_and_grow() {
+        initialize();
+        let mut vec: StableVec<u64, TEST_VEC> = StableVec::default();
+        for value in 0..200 {
+            vec.push(value);
+        }
+        assert_eq!(vec.len(), 200);
+        vec.set(100, 1000);
+        assert_eq!(vec.get(100), Some(1000));
+        assert_eq!(vec.get(199), Some(199));
+        assert_eq!(vec.get(200), None);
+        assert_eq!(vec.iter().filter(|value| *value < 200).count(), 199);
+    }
+
+    #[test]
+    fn blobs_replace_the_previous_value() {
+        initialize();
+        assert_eq!(read_blob(TEST_MAP), None);
+        write_blob(TEST_MAP, b"first");
+        write_blob(TEST_MAP, b"second");
+        assert_eq!(read_blob(TEST_MAP), Some(b"second".to_vec()));
+    }
+}
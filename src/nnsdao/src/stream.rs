@@ -0,0 +1,281 @@
+use crate::disburse::{Amount, Disbursement, MAX_DISBURSEMENT_TRIES};
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_ledger_types::{BlockIndex, Subaccount};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Payment stream or vesting schedule proposed to the DAO
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct StreamArg {
+    pub recipient: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    /// Token and total amount paid over the whole schedule
+    pub total: Amount,
+    /// Time of the first period, defaults to the time the proposal is executed
+    pub start: Option<u64>,
+    /// Nothing is released before `start + cliff`, vested periods are paid at once after it
+    pub cliff: u64,
+    /// Length of a period in nanoseconds
+    pub period: u64,
+    /// Number of periods the total is split into
+    pub periods: u64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum StreamState {
+    Active,
+    Completed,
+    Cancelled,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct StreamPayment {
+    pub amount: u64,
+    pub queued_at: u64,
+    pub block_index: Option<BlockIndex>,
+    pub error: Option<String>,
+    /// Paid, or failed after the last retry
+    pub settled: bool,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct Stream {
+    pub id: u64,
+    pub recipient: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub total: Amount,
+    pub start: u64,
+    pub cliff: u64,
+    pub period: u64,
+    pub periods: u64,
+    pub proposal_id: Option<u64>,
+    pub state: StreamState,
+    /// Amount handed to the disbursement queue so far
+    pub released: u64,
+    /// Amount confirmed by the ledger
+    pub paid: u64,
+    pub payments: Vec<StreamPayment>,
+}
+
+impl Stream {
+    /// Amount vested at `now`, one share is released at the beginning of every period
+    pub fn vested(&self, now: u64) -> u64 {
+        if now < self.start.saturating_add(self.cliff) {
+            return 0;
+        }
+        let elapsed = (now - self.start) / self.period + 1;
+        if elapsed >= self.periods {
+            return self.total.value();
+        }
+        (self.total.value() as u128 * elapsed as u128 / self.periods as u128) as u64
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct StreamService {
+    #[serde(default)]
+    pub streams: HashMap<u64, Stream>,
+    #[serde(default)]
+    pub next_id: u64,
+}
+
+impl StreamService {
+    pub fn validate(arg: &StreamArg) -> Result<(), String> {
+        if arg.period == 0 || arg.periods == 0 {
+            return Err(String::from("Stream must have at least one period"));
+        }
+        if arg.total.value() < arg.periods {
+            return Err(String::from("Stream total must cover every period"));
+        }
+        Ok(())
+    }
+
+    pub fn create(&mut self, arg: StreamArg, proposal_id: Option<u64>) -> Stream {
+        let id = self.next_id;
+        self.next_id += 1;
+        let stream = Stream {
+            id,
+            recipient: arg.recipient,
+            to_subaccount: arg.to_subaccount,
+            total: arg.total,
+            start: arg.start.unwrap_or_else(ic::time),
+            cliff: arg.cliff,
+            period: arg.period,
+            periods: arg.periods,
+            proposal_id,
+            state: StreamState::Active,
+            released: 0,
+            paid: 0,
+            payments: vec![],
+        };
+        self.streams.insert(id, stream.clone());
+        stream
+    }
+
+    pub fn get_stream(&self, id: u64) -> Result<Stream, String> {
+        self.streams
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| String::from("Stream does not exist"))
+    }
+
+    pub fn stream_list(&self) -> Vec<Stream> {
+        let mut list: Vec<Stream> = self.streams.values().cloned().collect();
+        list.sort_by_key(|stream| stream.id);
+        list
+    }
+
+    /// Stop future releases, payments already queued are still made
+    pub fn cancel(&mut self, id: u64) -> Result<Stream, String> {
+        let stream = self
+            .streams
+            .get_mut(&id)
+            .ok_or_else(|| String::from("Stream does not exist"))?;
+        if stream.state != StreamState::Active {
+            return Err(String::from("Stream is not active"));
+        }
+        stream.state = StreamState::Cancelled;
+        Ok(stream.clone())
+    }
+
+    /// Disbursements for everything vested but not yet released
+    pub fn release_due(&mut self, now: u64) -> Vec<Disbursement> {
+        let mut disbursements = vec![];
+        for stream in self.streams.values_mut() {
+            if stream.state != StreamState::Active {
+                continue;
+            }
+            let amount = stream.vested(now) - stream.released;
+            if amount == 0 {
+                continue;
+            }
+            stream.released += amount;
+            if stream.released >= stream.total.value() {
+                stream.state = StreamState::Completed;
+            }
+            let payment = stream.payments.len() as u64;
+            stream.payments.push(StreamPayment {
+                amount,
+                queued_at: now,
+                block_index: None,
+                error: None,
+                settled: false,
+            });
+            disbursements.push(Disbursement {
+                canister: ic::id().to_text(),
+                token_idf: format!("stream-{}", stream.id),
+                from_subaccount: None,
+                to: stream.recipient,
+                to_subaccount: stream.to_subaccount,
+                amount: stream.total.with_value(amount),
+                try_num: 0,
+                proposal_id: stream.proposal_id,
+                bounty_id: None,
+                stream_id: Some(stream.id),
+                stream_payment: Some(payment),
+                budget_spend: None,
+                token: None,
+            });
+        }
+        disbursements
+    }
+
+    /// Record the outcome of a stream payment
+    pub fn settle(&mut self, disbursement: &Disbursement, result: &Result<BlockIndex, String>) {
+        let stream = match disbursement
+            .stream_id
+            .and_then(|id| self.streams.get_mut(&id))
+        {
+            Some(stream) => stream,
+            None => return,
+        };
+        let amount = disbursement.amount.value();
+        // Disbursements queued before payments were indexed are matched by amount
+        let payment = match disbursement.stream_payment {
+            Some(index) => stream.payments.get_mut(index as usize),
+            None => stream
+                .payments
+                .iter_mut()
+                .find(|payment| !payment.settled && payment.amount == amount),
+        };
+        match result {
+            Ok(block) => {
+                stream.paid += amount;
+                if let Some(payment) = payment {
+                    payment.block_index = Some(*block);
+                    payment.error = None;
+                    payment.settled = true;
+                }
+            }
+            Err(err) => {
+                if let Some(payment) = payment {
+                    payment.error = Some(err.clone());
+                    payment.settled = disbursement.try_num >= MAX_DISBURSEMENT_TRIES;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{principal, setup};
+
+    const PERIOD: u64 = 1_000;
+
+    fn arg(total: u64, cliff: u64, periods: u64) -> StreamArg {
+        StreamArg {
+            recipient: principal(2),
+            to_subaccount: None,
+            total: Amount::NDP(total),
+            start: Some(10_000),
+            cliff,
+            period: PERIOD,
+            periods,
+        }
+    }
+
+    #[test]
+    fn one_share_vests_at_the_start_of_every_period() {
+        let stream = StreamService::default().create(arg(100, 0, 4), None);
+        assert_eq!(stream.vested(9_999), 0);
+        assert_eq!(stream.vested(10_000), 25);
+        assert_eq!(stream.vested(10_999), 25);
+        assert_eq!(stream.vested(11_000), 50);
+        assert_eq!(stream.vested(13_000), 100);
+        assert_eq!(stream.vested(u64::MAX), 100);
+    }
+
+    #[test]
+    fn nothing_vests_before_the_cliff() {
+        let stream = StreamService::default().create(arg(100, 2 * PERIOD, 4), None);
+        assert_eq!(stream.vested(11_999), 0);
+        assert_eq!(stream.vested(12_000), 75);
+    }
+
+    #[test]
+    fn shares_round_down_until_the_last_period() {
+        let stream = StreamService::default().create(arg(10, 0, 3), None);
+        assert_eq!(stream.vested(10_000), 3);
+        assert_eq!(stream.vested(11_000), 6);
+        assert_eq!(stream.vested(12_000), 10);
+    }
+
+    #[test]
+    fn payments_of_the_same_amount_are_settled_by_index() {
+        setup(principal(1));
+        let mut service = StreamService::default();
+        service.create(arg(100, 0, 4), None);
+        let first = service.release_due(10_000).remove(0);
+        let second = service.release_due(11_000).remove(0);
+        assert_eq!(first.amount.value(), second.amount.value());
+
+        service.settle(&second, &Ok(7));
+        let stream = service.get_stream(0).unwrap();
+        assert!(!stream.payments[0].settled);
+        assert_eq!(stream.payments[1].block_index, Some(7));
+        assert_eq!(stream.paid, 25);
+    }
+}
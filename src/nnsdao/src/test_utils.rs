@@ -0,0 +1,39 @@
+//! Helpers shared by the unit tests.
+
+use crate::canister::token_ledger::test_ledger;
+use crate::stable;
+use ic_cdk::export::Principal;
+use ic_kit::MockContext;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+/// Poll a future once, the test drives it again when it is pending
+pub fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    future.poll(&mut Context::from_waker(Waker::noop()))
+}
+
+/// A fresh canister state for the calling thread, with the test ledger installed
+pub fn setup(caller: Principal) -> &'static mut MockContext {
+    let context = MockContext::new()
+        .with_id(principal(0))
+        .with_caller(caller)
+        .inject();
+    stable::initialize();
+    test_ledger::install();
+    context
+}
+
+/// A distinct principal for each number
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n, 1])
+}
+
+/// Drive a future that never waits to its output
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    match poll(future.as_mut()) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("future is waiting on the test ledger"),
+    }
+}
@@ -1,19 +1,195 @@
+use crate::certified::CertifiedService;
+use crate::config::DaoConfig;
+use crate::disburse::Amount;
+use crate::events::EventKind;
+use crate::logger::{LogLevel, LogValue};
+use crate::treasury::{FlowDirection, FlowKind, TreasuryEntry};
 use crate::Data;
 use ic_cdk::export::Principal;
 use ic_kit::ic;
 use ic_kit::interfaces::management::{CanisterStatus, CanisterStatusResponse, WithCanisterId};
 use ic_kit::interfaces::Method;
+use std::future::Future;
+
+/// The canister state for calls that keep it across awaits, which `ic::with_mut` can not do.
+/// Such calls must store their changes before awaiting, see `DaoService::vote`
+#[allow(deprecated)]
+pub fn state() -> &'static mut Data {
+    ic::get_mut::<Data>()
+}
 
 pub fn is_owner() -> Result<(), String> {
-    let data = ic::get::<Data>();
-    let caller = ic_cdk::caller();
+    ic::with(|data: &Data| {
+        let caller = ic::caller();
+
+        data.owners.is_owner(caller)
+    })
+}
+
+/// Append to the governance event log
+pub fn record_event(caller: Principal, kind: EventKind) {
+    ic::with_mut(|data: &mut Data| {
+        let event = data.events.record(caller, kind);
+        data.analytics.observe(&event);
+        data.reputation
+            .observe(&event, &data.dao.dao_config().reputation);
+        data.certified.set_event_tip(data.events.tip.clone());
+        data.certified.certify();
+    })
+}
+
+/// Change the certified tree and set its new root hash as certified data
+pub fn certify(change: impl FnOnce(&mut CertifiedService)) {
+    ic::with_mut(|data: &mut Data| {
+        change(&mut data.certified);
+        data.certified.certify();
+    })
+}
+
+/// Build the certified tree from scratch
+pub fn rebuild_certified() {
+    ic::with_mut(|data: &mut Data| {
+        let info = data.dao.dao_info().unwrap_or_default();
+        let proposals = data.dao.basic.proposals(true);
+        data.certified.rebuild(
+            &info,
+            data.events.tip.clone(),
+            data.dao.member_list.iter(),
+            proposals.into_iter(),
+        );
+        data.certified.certify();
+    })
+}
+
+/// Percent the escrowed tokens of a vote cast now are scaled by, see `reputation`
+pub fn vote_weight_percent(voter: Principal, config: &DaoConfig) -> u64 {
+    ic::with(|data: &Data| {
+        data.reputation
+            .vote_weight_percent(voter, ic::time(), &config.reputation)
+    })
+}
+
+/// Compute the statistics from scratch
+pub fn rebuild_analytics() {
+    ic::with_mut(|data: &mut Data| {
+        data.analytics.rebuild(
+            data.dao.member_list.values(),
+            data.dao.basic.proposals(true).into_iter(),
+        );
+    })
+}
+
+/// Certify the restored tree again and build what a state written before it lacks
+pub fn restore_derived_state() {
+    let (certified, analytics) =
+        ic::with(|data: &Data| (data.certified.is_built(), data.analytics.is_built()));
+    if certified {
+        ic::with(|data: &Data| data.certified.certify());
+    } else {
+        rebuild_certified();
+    }
+    if !analytics {
+        rebuild_analytics();
+    }
+}
 
-    data.owners.is_owner(caller)
+pub fn log_message(
+    level: LogLevel,
+    caller: Principal,
+    method: &str,
+    message: String,
+    fields: Vec<(String, LogValue)>,
+) {
+    ic::with_mut(|data: &mut Data| data.logger.log(level, caller, method, message, fields))
 }
 
-pub fn log_message(canister: String, caller: Principal, method: String, kv: Vec<(String, String)>) {
-    let data = ic::get_mut::<Data>();
-    data.logger.log_format_message(canister, caller, method, kv)
+/// Log an update call of the current caller
+pub fn log_call(method: &str) {
+    log_message(
+        LogLevel::Info,
+        ic::caller(),
+        method,
+        String::from("ok"),
+        vec![],
+    )
+}
+
+/// Log the outcome of an update call, failed calls are logged as errors
+pub fn log_result<T>(method: &str, result: &Result<T, String>) {
+    match result {
+        Ok(_) => log_call(method),
+        Err(_) => log_error(method, result),
+    }
+}
+
+/// Log a failed call, successful ones are not logged
+pub fn log_error<T>(method: &str, result: &Result<T, String>) {
+    if let Err(err) = result {
+        log_message(LogLevel::Error, ic::caller(), method, err.clone(), vec![]);
+    }
+}
+
+/// Run an update call and log its outcome
+pub fn logged<T>(method: &str, call: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let result = call();
+    log_result(method, &result);
+    result
+}
+
+/// Run a read-only call and log it only when it fails
+pub async fn logged_errors_async<T>(
+    method: &str,
+    call: impl Future<Output = Result<T, String>>,
+) -> Result<T, String> {
+    let result = call.await;
+    log_error(method, &result);
+    result
+}
+
+pub async fn logged_async<T>(
+    method: &str,
+    call: impl Future<Output = Result<T, String>>,
+) -> Result<T, String> {
+    let result = call.await;
+    log_result(method, &result);
+    result
+}
+
+pub fn record_treasury_flow(
+    direction: FlowDirection,
+    kind: FlowKind,
+    amount: Amount,
+    counterparty: Principal,
+    proposal_id: Option<u64>,
+) {
+    ic::with_mut(|data: &mut Data| {
+        data.treasury.record(TreasuryEntry {
+            timestamp: ic::time(),
+            direction,
+            kind,
+            amount,
+            counterparty,
+            proposal_id,
+        })
+    })
+}
+
+/// Record a movement of a registered token
+pub fn record_token_flow(
+    direction: FlowDirection,
+    kind: FlowKind,
+    symbol: &str,
+    amount: u64,
+    counterparty: Principal,
+    proposal_id: Option<u64>,
+) {
+    record_treasury_flow(
+        direction,
+        kind,
+        Amount::Token(symbol.to_string(), amount),
+        counterparty,
+        proposal_id,
+    )
 }
 
 pub fn encode_token(canister_id: Principal, token: u32) -> String {
@@ -80,7 +256,7 @@ pub async fn canister_status() -> Result<CanisterStatusResponse, String> {
     CanisterStatus::perform(
         Principal::management_canister(),
         (WithCanisterId {
-            canister_id: ic_cdk::id(),
+            canister_id: ic::id(),
         },),
     )
     .await
@@ -0,0 +1,199 @@
+use crate::canister::token_ledger::TokenConfig;
+use crate::disburse::Amount;
+use candid::{CandidType, Principal};
+use ic_kit::ic;
+use ic_ledger_types::{BlockIndex, Subaccount};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum FlowDirection {
+    Inflow,
+    Outflow,
+}
+
+/// Where a treasury movement comes from
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum FlowKind {
+    /// Deposit escrowed when submitting a proposal
+    ProposalFee,
+    /// Tokens escrowed as voting weight
+    VoteEscrow,
+    /// Escrowed deposits and votes given back
+    Refund,
+    /// Share of the losing side paid to the winning side
+    Reward,
+    /// Payments made through the disbursement queue
+    Disbursement,
+    /// Funds received on a deposit subaccount
+    Deposit,
+    /// Ledger fees paid by the treasury
+    Fee,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct TreasuryEntry {
+    pub timestamp: u64,
+    pub direction: FlowDirection,
+    pub kind: FlowKind,
+    pub amount: Amount,
+    pub counterparty: Principal,
+    pub proposal_id: Option<u64>,
+}
+
+/// A refund or reward paid to one recipient
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct PayoutReport {
+    pub recipient: Principal,
+    pub token: String,
+    /// Escrowed amount owed to the recipient
+    pub gross: u64,
+    pub fee: u128,
+    /// Amount the recipient actually received
+    pub net: u128,
+    pub block_index: Option<BlockIndex>,
+    pub error: Option<String>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, Debug)]
+pub struct FlowTotals {
+    pub inflow: u128,
+    pub outflow: u128,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct TreasuryBalance {
+    pub token: String,
+    pub subaccount: Option<Subaccount>,
+    pub balance: Result<u128, String>,
+}
+
+#[derive(CandidType, Clone, Debug)]
+pub struct TreasuryOverview {
+    pub balances: Vec<TreasuryBalance>,
+    /// Number of tracked subaccounts, their balances are listed a page at a time
+    pub subaccount_count: u64,
+    pub flows: Vec<(String, FlowTotals)>,
+    pub recent_entries: Vec<TreasuryEntry>,
+}
+
+/// A subaccount of the DAO canister and the token it was handed out for
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct TrackedSubaccount {
+    pub subaccount: Subaccount,
+    pub token: String,
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize, Debug)]
+pub struct TreasuryService {
+    /// Subaccounts of the DAO canister holding funds besides the default one
+    #[serde(default)]
+    pub subaccounts: Vec<TrackedSubaccount>,
+    #[serde(default)]
+    pub flows: HashMap<String, FlowTotals>,
+    #[serde(default)]
+    pub entries: Vec<TreasuryEntry>,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+/// Most subaccount balances fetched by one overview
+const MAX_SUBACCOUNT_PAGE: usize = 50;
+
+impl Default for TreasuryService {
+    fn default() -> Self {
+        Self {
+            subaccounts: Vec::new(),
+            flows: HashMap::new(),
+            entries: Vec::new(),
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+impl TreasuryService {
+    pub fn track_subaccount(&mut self, subaccount: Subaccount, token: &str) {
+        if !self
+            .subaccounts
+            .iter()
+            .any(|item| item.subaccount == subaccount)
+        {
+            self.subaccounts.push(TrackedSubaccount {
+                subaccount,
+                token: token.to_string(),
+            });
+        }
+    }
+
+    /// Add a movement to the running totals and the journal of recent entries
+    pub fn record(&mut self, entry: TreasuryEntry) {
+        let totals = self.flows.entry(entry.amount.token()).or_default();
+        match entry.direction {
+            FlowDirection::Inflow => totals.inflow += entry.amount.value() as u128,
+            FlowDirection::Outflow => totals.outflow += entry.amount.value() as u128,
+        }
+
+        self.entries.push(entry);
+        if self.entries.len() > self.max_entries {
+            let overflow = self.entries.len() - self.max_entries;
+            self.entries.drain(..overflow);
+        }
+    }
+
+    /// Balances of the default account for every token and of a page of the tracked
+    /// subaccounts, each only for the token it was handed out for
+    pub async fn overview(
+        &self,
+        tokens: &[TokenConfig],
+        offset: usize,
+        limit: usize,
+    ) -> TreasuryOverview {
+        let dao = ic::id();
+        let mut balances = vec![];
+
+        for token in tokens {
+            balances.push(TreasuryBalance {
+                token: token.symbol.clone(),
+                subaccount: None,
+                balance: token.ledger().balance_of(dao, None).await,
+            });
+        }
+        let page = self
+            .subaccounts
+            .iter()
+            .skip(offset)
+            .take(limit.min(MAX_SUBACCOUNT_PAGE));
+        for tracked in page {
+            let balance = match tokens.iter().find(|token| token.symbol == tracked.token) {
+                Some(token) => {
+                    token
+                        .ledger()
+                        .balance_of(dao, Some(tracked.subaccount))
+                        .await
+                }
+                None => Err(format!("Token {} is not registered", tracked.token)),
+            };
+            balances.push(TreasuryBalance {
+                token: tracked.token.clone(),
+                subaccount: Some(tracked.subaccount),
+                balance,
+            });
+        }
+
+        TreasuryOverview {
+            balances,
+            subaccount_count: self.subaccounts.len() as u64,
+            flows: self
+                .flows
+                .iter()
+                .map(|(token, totals)| (token.clone(), totals.clone()))
+                .collect(),
+            recent_entries: self.entries.iter().rev().take(100).cloned().collect(),
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Layout of the state kept in stable memory across upgrades.
+//!
+//! The state is written as `MAGIC | version: u32 | length: u64 | cbor`, all little endian.
+//! Older versions are brought up to date by running the migrations in order. Anything that
+//! can not be read makes `post_upgrade` trap, which rolls the upgrade back instead of
+//! starting with an empty state.
+//!
+//! Since version 2 members, proposals and logs live in the collections of `stable` and the
+//! rest of the state is kept there as a blob. Versions 0 and 1 were written at offset 0 with
+//! the collections inline, they are moved into stable collections on the first upgrade.
+
+use crate::dao::MemberItems;
+use crate::logger::LogMessageData;
+use crate::sdk::Proposal;
+use crate::{stable, Data, DataV0};
+use candid::Principal;
+use ic_cdk::api::stable::{stable_read, stable_size};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const MAGIC: &[u8; 4] = b"NDAO";
+pub const HEADER_LEN: usize = 16;
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// Version of the layout written by `pre_upgrade`
+pub const STATE_VERSION: u32 = 2;
+
+/// Collections that versions 0 and 1 kept inline in the state
+#[derive(Deserialize, Default)]
+struct LegacyCollections {
+    #[serde(default)]
+    dao: LegacyDao,
+    #[serde(default)]
+    logger: LegacyLogger,
+}
+
+#[derive(Deserialize, Default)]
+struct LegacyDao {
+    #[serde(default)]
+    member_list: HashMap<Principal, MemberItems>,
+    #[serde(default)]
+    basic: LegacyBasic,
+}
+
+#[derive(Deserialize, Default)]
+struct LegacyBasic {
+    #[serde(default)]
+    proposal_list: HashMap<u64, Proposal>,
+}
+
+#[derive(Deserialize, Default)]
+struct LegacyLogger {
+    #[serde(default)]
+    queue: Vec<LogMessageData>,
+}
+
+/// State as read from stable memory, before migration
+enum VersionedState {
+    /// `DataV0` written without a header, heartbeat settings were not kept
+    V0(DataV0, LegacyCollections),
+    /// `Data` with the collections inline
+    V1(Data, LegacyCollections),
+    /// `Data` with the collections in stable memory
+    V2(Data),
+}
+
+/// The proposal deposit of version 0 is in base units, it is whole tokens since version 1.
+/// Services added after version 0 start empty.
+fn v0_to_v1(mut data: DataV0) -> Data {
+    let mut config = data.dao.dao_config();
+    config.deposit_units_to_whole();
+    data.dao.set_dao_config(config);
+    Data {
+        owners: data.owners,
+        logger: data.logger,
+        dao: data.dao,
+        disburse: data.disburse,
+        treasury: Default::default(),
+        bounty: Default::default(),
+        stream: Default::default(),
+        budget: Default::default(),
+        deposit: Default::default(),
+        events: Default::default(),
+        certified: Default::default(),
+        analytics: Default::default(),
+        reputation: Default::default(),
+        run_heartbeat: true,
+        heartbeat_last_beat: 0,
+        heartbeat_interval_seconds: 2,
+        heartbeat_started: None,
+    }
+}
+
+/// Move the inline collections into stable memory, which must already be formatted
+fn v1_to_v2(mut data: Data, collections: LegacyCollections) -> Data {
+    for (principal, member) in collections.dao.member_list {
+        data.dao.member_list.insert(principal, member);
+    }
+    for (id, proposal) in collections.dao.basic.proposal_list {
+        data.dao.basic.proposal_list.insert(id, proposal);
+    }
+    // pushed in order, so the ring buffer position of the logger stays valid
+    for message in collections.logger.queue {
+        data.logger.queue.push(message);
+    }
+    data
+}
+
+/// Apply the migrations in order until the state is at the current version
+fn migrate(mut state: VersionedState) -> Data {
+    loop {
+        state = match state {
+            VersionedState::V0(data, collections) => {
+                VersionedState::V1(v0_to_v1(data), collections)
+            }
+            VersionedState::V1(data, collections) => {
+                VersionedState::V2(v1_to_v2(data, collections))
+            }
+            VersionedState::V2(data) => return data,
+        }
+    }
+}
+
+pub fn encode_state(data: &Data) -> Result<Vec<u8>, String> {
+    let body = serde_cbor::to_vec(data).map_err(|e| format!("Failed to serialize: {}", e))?;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+/// Decode a state written by `encode_state` and migrate it to the current version
+pub fn decode_state(bytes: &[u8]) -> Result<Data, String> {
+    let (version, body) = split_header(bytes)?;
+    let state = match version {
+        1 => VersionedState::V1(deserialize(body)?, deserialize(body)?),
+        2 => VersionedState::V2(deserialize(body)?),
+        _ => return Err(format!("Unknown state version {}", version)),
+    };
+    Ok(migrate(state))
+}
+
+fn deserialize<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, String> {
+    serde_cbor::from_slice(bytes).map_err(|e| format!("Failed to deserialize: {}", e))
+}
+
+pub fn split_header(bytes: &[u8]) -> Result<(u32, &[u8]), String> {
+    if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+        return Err(String::from("Missing state header"));
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&bytes[4..8]);
+    let version = u32::from_le_bytes(version);
+    if version > STATE_VERSION {
+        return Err(format!(
+            "State version {} is newer than this canister ({})",
+            version, STATE_VERSION
+        ));
+    }
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&bytes[8..HEADER_LEN]);
+    let length = u64::from_le_bytes(length) as usize;
+    bytes
+        .get(HEADER_LEN..HEADER_LEN + length)
+        .map(|body| (version, body))
+        .ok_or_else(|| String::from("State is truncated"))
+}
+
+pub fn save_stable_state(data: &Data) -> Result<(), String> {
+    if !stable::is_initialized() {
+        return Err(String::from("Stable memory is not formatted"));
+    }
+    let bytes = encode_state(data)?;
+    stable::write_blob(stable::UPGRADE_STATE, &bytes);
+    Ok(())
+}
+
+pub fn load_stable_state() -> Result<Data, String> {
+    if stable::is_initialized() {
+        let bytes = stable::read_blob(stable::UPGRADE_STATE)
+            .ok_or_else(|| String::from("State is missing from stable memory"))?;
+        return decode_state(&bytes);
+    }
+
+    let available = stable_size() as u64 * WASM_PAGE_SIZE;
+    if available < HEADER_LEN as u64 {
+        return Err(String::from("Stable memory is empty"));
+    }
+    let mut bytes = vec![0u8; available as usize];
+    stable_read(0, &mut bytes);
+    let state = if &bytes[..4] == MAGIC {
+        let (version, body) = split_header(&bytes)?;
+        if version != 1 {
+            return Err(format!(
+                "State version {} must be in stable collections",
+                version
+            ));
+        }
+        VersionedState::V1(deserialize(body)?, deserialize(body)?)
+    } else {
+        VersionedState::V0(deserialize_legacy(&bytes)?, deserialize_legacy(&bytes)?)
+    };
+    // the legacy state is in memory now, its pages become the superblock
+    stable::initialize();
+    Ok(migrate(state))
+}
+
+/// `DataV0` was written without its length, the zeroed rest of the memory follows it
+fn deserialize_legacy<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> Result<T, String> {
+    let mut deserializer = serde_cbor::Deserializer::from_slice(bytes);
+    T::deserialize(&mut deserializer).map_err(|e| format!("Failed to deserialize: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dao::JoinDaoParams;
+    use crate::events::EventKind;
+    use crate::logger::LogLevel;
+    use crate::test_utils::{principal, setup};
+    use crate::tools;
+    use ic_kit::ic;
+    use serde_cbor::Value;
+
+    fn with_header(version: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        bytes.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn field<'a>(value: &'a mut Value, name: &str) -> &'a mut Value {
+        match value {
+            Value::Map(fields) => fields.get_mut(&Value::Text(name.to_owned())).unwrap(),
+            _ => panic!("{} is not in a map", name),
+        }
+    }
+
+    fn insert(value: &mut Value, name: &str, inserted: Value) {
+        if let Value::Map(fields) = value {
+            fields.insert(Value::Text(name.to_owned()), inserted);
+        }
+    }
+
+    #[test]
+    fn encoded_state_decodes_to_the_same_state() {
+        setup(principal(1));
+        let mut data = Data::default();
+        data.owners.add_owner(principal(1));
+        data.heartbeat_interval_seconds = 7;
+        data.heartbeat_started = Some(1);
+        let data = decode_state(&encode_state(&data).unwrap()).unwrap();
+        assert!(data.owners.is_owner(principal(1)).is_ok());
+        assert_eq!(data.heartbeat_interval_seconds, 7);
+        assert_eq!(data.heartbeat_started, None);
+    }
+
+    #[test]
+    fn derived_state_is_kept_in_the_blob() {
+        setup(principal(1));
+        tools::rebuild_certified();
+        tools::rebuild_analytics();
+        ic::with_mut(|data: &mut Data| {
+            data.dao
+                .join(principal(2), JoinDaoParams::default())
+                .unwrap()
+        });
+        let like = EventKind::LikeComment {
+            proposal_id: 0,
+            comment: 0,
+            author: principal(2),
+        };
+        tools::record_event(principal(3), like);
+
+        let (root, data) = ic::with(|data: &Data| {
+            let root = data.certified.clone();
+            (root, decode_state(&encode_state(data).unwrap()).unwrap())
+        });
+        assert!(data.certified.is_built());
+        assert_eq!(format!("{:?}", data.certified), format!("{:?}", root));
+        assert!(data.analytics.is_built());
+        assert_eq!(data.analytics.analytics(ic::time(), None).members, 1);
+        let config = data.dao.dao_config().reputation;
+        assert!(data.reputation.score(principal(2), ic::time(), &config) > 0.0);
+    }
+
+    #[test]
+    fn unreadable_states_are_refused() {
+        setup(principal(1));
+        let bytes = encode_state(&Data::default()).unwrap();
+        let mut newer = bytes.clone();
+        newer[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(decode_state(&newer).unwrap_err().contains("newer"));
+        assert_eq!(
+            decode_state(&bytes[..bytes.len() - 1]).unwrap_err(),
+            "State is truncated"
+        );
+        assert_eq!(
+            decode_state(&bytes[4..]).unwrap_err(),
+            "Missing state header"
+        );
+    }
+
+    #[test]
+    fn v1_collections_move_to_stable_memory() {
+        setup(principal(1));
+        let member = ic::with_mut(|data: &mut Data| {
+            let member = data
+                .dao
+                .join(principal(2), JoinDaoParams::default())
+                .unwrap();
+            data.dao.member_list.remove(&principal(2));
+            member
+        });
+        let message = LogMessageData {
+            timeNanos: 1,
+            message: String::from("message"),
+            level: LogLevel::Info,
+            caller: None,
+            method: String::new(),
+            fields: vec![],
+        };
+        let data = Data {
+            heartbeat_interval_seconds: 5,
+            ..Default::default()
+        };
+        let mut state: Value = deserialize(&serde_cbor::to_vec(&data).unwrap()).unwrap();
+        let members: HashMap<Principal, MemberItems> = HashMap::from([(principal(2), member)]);
+        insert(
+            field(&mut state, "dao"),
+            "member_list",
+            serde_cbor::value::to_value(members).unwrap(),
+        );
+        insert(
+            field(&mut state, "logger"),
+            "queue",
+            serde_cbor::value::to_value(vec![message]).unwrap(),
+        );
+
+        let body = serde_cbor::to_vec(&state).unwrap();
+        let data = decode_state(&with_header(1, &body)).unwrap();
+        assert_eq!(data.heartbeat_interval_seconds, 5);
+        assert!(data.dao.member_list.get(&principal(2)).is_some());
+        assert_eq!(data.logger.queue.len(), 1);
+    }
+
+    #[test]
+    fn v0_deposit_becomes_whole_tokens() {
+        setup(principal(1));
+        let mut data = DataV0::default();
+        let mut config = data.dao.dao_config();
+        config.proposal_deposit = 250_000_000;
+        data.dao.set_dao_config(config);
+        let data = v0_to_v1(data);
+        assert_eq!(data.dao.dao_config().proposal_deposit, 3);
+    }
+}